@@ -0,0 +1,591 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::{header, HeaderMap};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use moka::future::Cache;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::{config, messages::ServerErrors, ws::SocketId};
+
+// the parts of a Supabase access token we care about, `exp` and `aud` are
+// checked while decoding
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    // seconds since the epoch, compared with when the user's sessions were last revoked
+    iat: i64,
+}
+
+// a token that was signed by Supabase for us and hasn't expired
+pub struct VerifiedToken {
+    pub user_id: Uuid,
+    pub issued_at: i64,
+}
+
+// Checks the credentials a socket authenticates with
+pub struct Authenticator {
+    // Supabase access tokens, signed with the project's JWT secret
+    jwt: Option<(DecodingKey, Validation)>,
+    // the `verify.secret` UUID, kept until every client sends tokens
+    legacy_secret: bool,
+    // pages allowed to open sockets with the session cookie, browsers attach
+    // it to upgrades started by any site
+    cookie_origins: Vec<String>,
+}
+
+impl Authenticator {
+    pub fn from_env() -> Self {
+        let jwt = std::env::var("SUPABASE_JWT_SECRET").ok().map(|secret| {
+            Self::jwt(
+                &secret,
+                &config::env_or("SUPABASE_JWT_AUDIENCE", "authenticated".to_string()),
+            )
+        });
+
+        if jwt.is_none() {
+            println!("SUPABASE_JWT_SECRET not set, token authentication is disabled");
+        }
+
+        // no origins means the cookie is never used
+        let cookie_origins = config::env_or("AUTH_COOKIE_ORIGINS", String::new())
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        Self {
+            jwt,
+            legacy_secret: config::env_or("AUTH_LEGACY_SECRET", true),
+            cookie_origins,
+        }
+    }
+
+    fn jwt(secret: &str, audience: &str) -> (DecodingKey, Validation) {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+
+        (DecodingKey::from_secret(secret.as_bytes()), validation)
+    }
+
+    // whether the upgrade came from a page that may use the session cookie
+    pub fn cookie_allowed(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(header::ORIGIN)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|origin| self.cookie_origins.iter().any(|allowed| allowed == origin))
+    }
+
+    pub fn legacy_secret(&self) -> bool {
+        self.legacy_secret
+    }
+
+    pub fn verify_token(&self, token: &str) -> Result<VerifiedToken, ServerErrors> {
+        let Some((key, validation)) = &self.jwt else {
+            return Err(ServerErrors::Unauthorized);
+        };
+
+        let claims = jsonwebtoken::decode::<Claims>(token, key, validation)
+            .map_err(|e| {
+                println!("Rejected token: {}", e);
+                ServerErrors::InvalidToken
+            })?
+            .claims;
+
+        Ok(VerifiedToken {
+            user_id: Uuid::parse_str(&claims.sub).map_err(|_| ServerErrors::InvalidToken)?,
+            issued_at: claims.iat,
+        })
+    }
+}
+
+// compares secrets without leaking how much of them matched through timing
+pub fn secrets_match(a: &Uuid, b: &Uuid) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn locked_for(&self) -> Option<Duration> {
+        self.locked_for_at(Instant::now())
+    }
+
+    fn locked_for_at(&self, now: Instant) -> Option<Duration> {
+        self.locked_until?
+            .checked_duration_since(now)
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn fail(&mut self) {
+        self.fail_at(Instant::now())
+    }
+
+    // every failure past the free ones doubles how long we stop listening
+    fn fail_at(&mut self, now: Instant) {
+        self.count += 1;
+        if let Some(over) = self.count.checked_sub(Lockout::FREE_ATTEMPTS + 1) {
+            let lockout = Lockout::BASE_LOCKOUT.saturating_mul(1 << over.min(16));
+            self.locked_until = Some(now + lockout.min(Lockout::MAX_LOCKOUT));
+        }
+    }
+}
+
+// Failed authentication attempts, counted per socket and per user that was
+// tried, so secrets can't be guessed by retrying
+pub struct Lockout {
+    sockets: Cache<SocketId, Arc<Mutex<Failures>>>,
+    users: Cache<Uuid, Arc<Mutex<Failures>>>,
+    // failures after which the socket is closed
+    max_socket_failures: u32,
+}
+
+impl Lockout {
+    const FREE_ATTEMPTS: u32 = 3;
+    const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+    const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+    pub fn from_env() -> Self {
+        // failures are forgotten once nobody tried for a while
+        let forget_after = config::env_secs_or("AUTH_FAILURE_WINDOW_SECS", 60 * 60);
+
+        Self {
+            sockets: Cache::builder().time_to_idle(forget_after).build(),
+            users: Cache::builder().time_to_idle(forget_after).build(),
+            max_socket_failures: config::env_or("AUTH_MAX_FAILURES_PER_SOCKET", 5),
+        }
+    }
+
+    // how long until the socket or user may try again, if they are locked out
+    pub async fn locked_for(&self, socket_id: SocketId, user_id: Option<Uuid>) -> Option<Duration> {
+        let socket = match self.sockets.get(&socket_id).await {
+            Some(failures) => failures.lock().unwrap().locked_for(),
+            None => None,
+        };
+
+        let user = match user_id {
+            Some(user_id) => match self.users.get(&user_id).await {
+                Some(failures) => failures.lock().unwrap().locked_for(),
+                None => None,
+            },
+            None => None,
+        };
+
+        socket.max(user)
+    }
+
+    pub async fn fail(&self, socket_id: SocketId, user_id: Option<Uuid>) {
+        let failures = self
+            .sockets
+            .get_with(socket_id, async { Arc::default() })
+            .await;
+        failures.lock().unwrap().fail();
+
+        if let Some(user_id) = user_id {
+            let failures = self.users.get_with(user_id, async { Arc::default() }).await;
+            failures.lock().unwrap().fail();
+        }
+    }
+
+    pub async fn succeed(&self, socket_id: SocketId, user_id: Uuid) {
+        self.sockets.invalidate(&socket_id).await;
+        self.users.invalidate(&user_id).await;
+    }
+
+    // true once the socket failed too often to be given more chances
+    pub async fn exhausted(&self, socket_id: SocketId) -> bool {
+        match self.sockets.get(&socket_id).await {
+            Some(failures) => failures.lock().unwrap().count >= self.max_socket_failures,
+            None => false,
+        }
+    }
+}
+
+// The access token a client sent with its upgrade request, looked for in the
+// `Authorization` header, then the `token` query parameter, then the session cookie
+// if the request came from an allowed origin
+pub fn request_token(
+    headers: &HeaderMap,
+    query_token: Option<String>,
+    cookie_allowed: bool,
+) -> Option<String> {
+    bearer_token(headers)
+        .or(query_token)
+        .filter(|token| !token.is_empty())
+        .or_else(|| {
+            cookie_allowed
+                .then(|| session_cookie_token(headers))
+                .flatten()
+        })
+}
+
+// the token in the `Authorization` header, the only place HTTP endpoints look
+// since browsers attach cookies to requests made by other sites
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+// the Supabase helpers store the session as JSON in `sb-<project>-auth-token`,
+// split over `.0`, `.1`, ... cookies when it gets too long
+fn session_cookie_token(headers: &HeaderMap) -> Option<String> {
+    let mut chunks = Vec::new();
+    for cookie in headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+    {
+        let Some((name, value)) = cookie.trim().split_once('=') else {
+            continue;
+        };
+
+        if !name.starts_with("sb-") {
+            continue;
+        }
+
+        let index = match name.split_once("-auth-token") {
+            Some((_, "")) => 0,
+            Some((_, chunk)) => match chunk.strip_prefix('.').and_then(|i| i.parse().ok()) {
+                Some(index) => index,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        chunks.push((index, value));
+    }
+
+    chunks.sort_by_key(|(index, _)| *index);
+    let value: String = chunks.into_iter().map(|(_, value)| value).collect();
+    let session: serde_json::Value = serde_json::from_str(&percent_decode(&value)?).ok()?;
+
+    // older helpers stored `[access_token, refresh_token, ...]`
+    let token = match &session {
+        serde_json::Value::Array(parts) => parts.first(),
+        session => session.get("access_token"),
+    };
+
+    token?.as_str().map(|token| token.to_string())
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.bytes();
+    while let Some(byte) = rest.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+
+        let hex = [rest.next()?, rest.next()?];
+        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn cookies(cookies: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for cookie in cookies {
+            headers.append(header::COOKIE, HeaderValue::from_str(cookie).unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn lockout_doubles_after_the_free_attempts() {
+        let now = Instant::now();
+        let mut failures = Failures::default();
+
+        for _ in 0..Lockout::FREE_ATTEMPTS {
+            failures.fail_at(now);
+            assert_eq!(failures.locked_for_at(now), None);
+        }
+
+        for seconds in [1, 2, 4, 8] {
+            failures.fail_at(now);
+            assert_eq!(
+                failures.locked_for_at(now),
+                Some(Duration::from_secs(seconds))
+            );
+        }
+
+        // and is over once the time is up
+        assert_eq!(failures.locked_for_at(now + Duration::from_secs(8)), None);
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        let now = Instant::now();
+        let mut failures = Failures::default();
+
+        for _ in 0..100 {
+            failures.fail_at(now);
+        }
+
+        assert_eq!(failures.locked_for_at(now), Some(Lockout::MAX_LOCKOUT));
+    }
+
+    fn lockout() -> Lockout {
+        Lockout {
+            sockets: Cache::builder().build(),
+            users: Cache::builder().build(),
+            max_socket_failures: 5,
+        }
+    }
+
+    #[tokio::test]
+    async fn success_resets_the_lockout() {
+        let lockout = lockout();
+        let socket_id = SocketId::next();
+        let user_id = Uuid::new_v4();
+
+        for _ in 0..Lockout::FREE_ATTEMPTS + 1 {
+            lockout.fail(socket_id, Some(user_id)).await;
+        }
+        assert!(lockout.locked_for(socket_id, Some(user_id)).await.is_some());
+
+        lockout.succeed(socket_id, user_id).await;
+        assert_eq!(lockout.locked_for(socket_id, Some(user_id)).await, None);
+        assert!(!lockout.exhausted(socket_id).await);
+    }
+
+    #[tokio::test]
+    async fn user_lockout_follows_them_to_other_sockets() {
+        let lockout = lockout();
+        let user_id = Uuid::new_v4();
+
+        for _ in 0..Lockout::FREE_ATTEMPTS + 1 {
+            lockout.fail(SocketId::next(), Some(user_id)).await;
+        }
+
+        let fresh = SocketId::next();
+        assert_eq!(lockout.locked_for(fresh, None).await, None);
+        assert!(lockout.locked_for(fresh, Some(user_id)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn socket_is_exhausted_after_too_many_failures() {
+        let lockout = lockout();
+        let socket_id = SocketId::next();
+
+        for _ in 0..4 {
+            lockout.fail(socket_id, None).await;
+            assert!(!lockout.exhausted(socket_id).await);
+        }
+
+        lockout.fail(socket_id, None).await;
+        assert!(lockout.exhausted(socket_id).await);
+    }
+
+    #[test]
+    fn cookie_chunks_are_joined_in_order() {
+        let session = r#"{"access_token":"abc.def.ghi","refresh_token":"r"}"#;
+        let encoded = session.replace('"', "%22");
+        let (first, second) = encoded.split_at(20);
+
+        // the chunks arrive out of order and mixed with unrelated cookies
+        let headers = cookies(&[
+            &format!("theme=dark; sb-project-auth-token.1={second}"),
+            &format!("sb-project-auth-token.0={first}"),
+        ]);
+
+        assert_eq!(
+            session_cookie_token(&headers).as_deref(),
+            Some("abc.def.ghi")
+        );
+    }
+
+    #[test]
+    fn cookie_session_can_be_an_object_or_an_array() {
+        let object = cookies(&[r#"sb-project-auth-token={"access_token":"object"}"#]);
+        assert_eq!(session_cookie_token(&object).as_deref(), Some("object"));
+
+        let array = cookies(&[r#"sb-project-auth-token=["array","refresh",null]"#]);
+        assert_eq!(session_cookie_token(&array).as_deref(), Some("array"));
+
+        let neither = cookies(&[r#"sb-project-auth-token="nope""#]);
+        assert_eq!(session_cookie_token(&neither), None);
+    }
+
+    #[test]
+    fn bad_percent_escapes_are_rejected() {
+        assert_eq!(percent_decode("a%20b").as_deref(), Some("a b"));
+        assert_eq!(percent_decode("%7B%7d").as_deref(), Some("{}"));
+        assert_eq!(percent_decode("50%"), None);
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        // decodes to bytes that are not UTF-8
+        assert_eq!(percent_decode("%ff"), None);
+
+        let headers = cookies(&["sb-project-auth-token=%7B%2"]);
+        assert_eq!(session_cookie_token(&headers), None);
+    }
+
+    #[test]
+    fn cookie_needs_an_allowed_origin() {
+        let auth = Authenticator {
+            jwt: None,
+            legacy_secret: false,
+            cookie_origins: vec!["https://market.example".to_string()],
+        };
+
+        let mut headers = HeaderMap::new();
+        assert!(!auth.cookie_allowed(&headers));
+
+        headers.insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://evil.example"),
+        );
+        assert!(!auth.cookie_allowed(&headers));
+
+        headers.insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://market.example"),
+        );
+        assert!(auth.cookie_allowed(&headers));
+    }
+
+    #[test]
+    fn cookie_is_only_used_when_allowed() {
+        let headers = cookies(&[r#"sb-project-auth-token={"access_token":"cookie"}"#]);
+
+        assert_eq!(request_token(&headers, None, false), None);
+        assert_eq!(
+            request_token(&headers, None, true).as_deref(),
+            Some("cookie")
+        );
+        assert_eq!(
+            request_token(&headers, Some("query".to_string()), true).as_deref(),
+            Some("query")
+        );
+    }
+
+    const JWT_SECRET: &str = "super-secret-jwt-token";
+
+    fn token_auth() -> Authenticator {
+        Authenticator {
+            jwt: Some(Authenticator::jwt(JWT_SECRET, "authenticated")),
+            legacy_secret: false,
+            cookie_origins: Vec::new(),
+        }
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    fn sign(claims: serde_json::Value, secret: &str) -> String {
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn claims(sub: &str) -> serde_json::Value {
+        serde_json::json!({
+            "sub": sub,
+            "aud": "authenticated",
+            "iat": now(),
+            "exp": now() + 3600,
+        })
+    }
+
+    fn rejected(token: &str) -> bool {
+        matches!(
+            token_auth().verify_token(token),
+            Err(ServerErrors::InvalidToken)
+        )
+    }
+
+    #[test]
+    fn token_gives_the_user_and_when_it_was_issued() {
+        let user_id = Uuid::new_v4();
+        let claims = claims(&user_id.to_string());
+
+        let token = token_auth()
+            .verify_token(&sign(claims.clone(), JWT_SECRET))
+            .ok()
+            .unwrap();
+        assert_eq!(token.user_id, user_id);
+        assert_eq!(token.issued_at, claims["iat"].as_i64().unwrap());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let mut claims = claims(&Uuid::new_v4().to_string());
+        claims["iat"] = (now() - 7200).into();
+        claims["exp"] = (now() - 3600).into();
+
+        assert!(rejected(&sign(claims, JWT_SECRET)));
+    }
+
+    #[test]
+    fn token_for_another_audience_is_rejected() {
+        let mut claims = claims(&Uuid::new_v4().to_string());
+        claims["aud"] = "anon".into();
+
+        assert!(rejected(&sign(claims, JWT_SECRET)));
+    }
+
+    #[test]
+    fn token_needs_a_user() {
+        let mut missing = claims("");
+        missing.as_object_mut().unwrap().remove("sub");
+        assert!(rejected(&sign(missing, JWT_SECRET)));
+
+        assert!(rejected(&sign(claims("service_role"), JWT_SECRET)));
+    }
+
+    #[test]
+    fn token_needs_to_say_when_it_was_issued() {
+        let mut claims = claims(&Uuid::new_v4().to_string());
+        claims.as_object_mut().unwrap().remove("iat");
+
+        assert!(rejected(&sign(claims, JWT_SECRET)));
+    }
+
+    #[test]
+    fn token_signed_with_another_secret_is_rejected() {
+        let claims = claims(&Uuid::new_v4().to_string());
+
+        assert!(rejected(&sign(claims, "not-the-jwt-secret")));
+    }
+
+    #[test]
+    fn tokens_are_unauthorized_without_a_secret() {
+        let auth = Authenticator {
+            jwt: None,
+            legacy_secret: true,
+            cookie_origins: Vec::new(),
+        };
+        let token = sign(claims(&Uuid::new_v4().to_string()), JWT_SECRET);
+
+        assert!(matches!(
+            auth.verify_token(&token),
+            Err(ServerErrors::Unauthorized)
+        ));
+    }
+}
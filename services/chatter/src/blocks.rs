@@ -0,0 +1,80 @@
+use std::{collections::HashSet, sync::Arc};
+
+use moka::future::Cache;
+use sqlx::Row;
+use uuid::Uuid;
+
+// Who blocked whom, a blocked user can't message or see the typing and
+// presence of the user that blocked them, and the other way around
+pub struct BlockManager {
+    dbpool: sqlx::PgPool,
+    // users blocked by each user
+    blocked: Cache<Uuid, Arc<HashSet<Uuid>>>,
+}
+
+impl BlockManager {
+    pub fn new(dbpool: sqlx::PgPool) -> Self {
+        Self {
+            dbpool,
+            blocked: Cache::builder().max_capacity(10_000).build(),
+        }
+    }
+
+    pub async fn blocked_by(&self, user: &Uuid) -> Result<Arc<HashSet<Uuid>>, Arc<sqlx::Error>> {
+        self.blocked
+            .try_get_with_by_ref(user, async {
+                let rows = sqlx::query("SELECT blocked FROM blocks WHERE blocker = $1")
+                    .bind(user)
+                    .fetch_all(&self.dbpool)
+                    .await?;
+
+                Ok(Arc::new(
+                    rows.iter()
+                        .filter_map(|row| row.try_get::<Uuid, _>("blocked").ok())
+                        .collect(),
+                ))
+            })
+            .await
+    }
+
+    // true if either user blocked the other, errors count as blocked
+    pub async fn between(&self, a: &Uuid, b: &Uuid) -> bool {
+        for (blocker, blocked) in [(a, b), (b, a)] {
+            match self.blocked_by(blocker).await {
+                Ok(users) if !users.contains(blocked) => {}
+                Ok(_) => return true,
+                Err(e) => {
+                    println!("Failed to load blocks: {}", e);
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    pub async fn block(&self, blocker: &Uuid, blocked: &Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO blocks (blocker, blocked) VALUES ($1, $2) \
+             ON CONFLICT (blocker, blocked) DO NOTHING",
+        )
+        .bind(blocker)
+        .bind(blocked)
+        .execute(&self.dbpool)
+        .await?;
+
+        self.blocked.invalidate(blocker).await;
+        Ok(())
+    }
+
+    pub async fn unblock(&self, blocker: &Uuid, blocked: &Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM blocks WHERE blocker = $1 AND blocked = $2")
+            .bind(blocker)
+            .bind(blocked)
+            .execute(&self.dbpool)
+            .await?;
+
+        self.blocked.invalidate(blocker).await;
+        Ok(())
+    }
+}
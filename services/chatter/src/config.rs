@@ -0,0 +1,21 @@
+use std::{str::FromStr, time::Duration};
+
+// reads an optional setting from the environment, falling back to `default`
+// when it is unset and warning when it cannot be parsed
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    let Ok(value) = std::env::var(key) else {
+        return default;
+    };
+
+    match value.parse() {
+        Ok(value) => value,
+        Err(_) => {
+            println!("Invalid value for {key}: {value:?}, using the default");
+            default
+        }
+    }
+}
+
+pub fn env_secs_or(key: &str, default: u64) -> Duration {
+    Duration::from_secs(env_or(key, default))
+}
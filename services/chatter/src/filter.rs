@@ -0,0 +1,320 @@
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    // let the message through, the hit is only logged
+    Allow,
+    // replace whatever the rule matched
+    Redact,
+    // refuse the whole message
+    Reject,
+}
+
+impl FilterAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Redact => "redact",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+impl FromStr for FilterAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "redact" => Ok(Self::Redact),
+            "reject" => Ok(Self::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+// a rule as it is written in the `FILTER_RULES_FILE` JSON array
+#[derive(Deserialize)]
+struct RuleConfig {
+    name: String,
+    pattern: String,
+    action: FilterAction,
+}
+
+struct Rule {
+    name: String,
+    pattern: Regex,
+    action: FilterAction,
+}
+
+#[derive(Clone, Debug)]
+pub struct FilterHit {
+    pub rule: String,
+    pub action: FilterAction,
+}
+
+pub struct Filtered {
+    // the message with every redaction applied
+    pub message: String,
+    pub hits: Vec<FilterHit>,
+}
+
+impl Filtered {
+    pub fn rejected(&self) -> bool {
+        self.hits
+            .iter()
+            .any(|hit| hit.action == FilterAction::Reject)
+    }
+}
+
+// Rules every outgoing message is run through, in order, before it is stored
+pub struct FilterChain {
+    rules: Vec<Rule>,
+}
+
+impl FilterChain {
+    const REDACTED: &'static str = "[removed]";
+
+    const EMAIL: &'static str = r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b";
+    // bare domains only count in lowercase, so a missing space after a full stop isn't one
+    const LINK: &'static str = r"(?i:\b(?:https?://|www\.)\S+)|\b[a-z0-9-]+(?:\.[a-z0-9-]+)*\.(?:com|net|org|io|ca|co|me|ly|app|xyz|gg)\b(?:/\S*)?";
+    // North American numbers, area codes and exchanges never start with 0 or 1,
+    // which keeps ISBNs and other long numbers out
+    const PHONE_NUMBER: &'static str =
+        r"(?:\+?1[\s.-]?)?(?:\(\b[2-9]\d{2}\)|\b[2-9]\d{2})[\s.-]?[2-9]\d{2}[\s.-]?\d{4}\b";
+
+    pub fn from_env() -> Self {
+        let mut chain = Self { rules: Vec::new() };
+
+        if let Ok(path) = std::env::var("FILTER_WORDLIST_FILE") {
+            chain.add_wordlist(
+                &path,
+                config::env_or("FILTER_WORDLIST_ACTION", FilterAction::Redact),
+            );
+        }
+
+        if let Ok(path) = std::env::var("FILTER_RULES_FILE") {
+            chain.add_rules_file(&path);
+        }
+
+        // emails go first, their domain would otherwise be taken for a link,
+        // links and phone numbers are only logged unless configured otherwise
+        chain.add(
+            "email",
+            Self::EMAIL,
+            config::env_or("FILTER_EMAIL_ACTION", FilterAction::Redact),
+        );
+        chain.add(
+            "link",
+            Self::LINK,
+            config::env_or("FILTER_LINK_ACTION", FilterAction::Allow),
+        );
+        chain.add(
+            "phone-number",
+            Self::PHONE_NUMBER,
+            config::env_or("FILTER_PHONE_NUMBER_ACTION", FilterAction::Allow),
+        );
+
+        chain
+    }
+
+    fn add(&mut self, name: &str, pattern: &str, action: FilterAction) {
+        match Regex::new(pattern) {
+            Ok(pattern) => self.rules.push(Rule {
+                name: name.to_string(),
+                pattern,
+                action,
+            }),
+            Err(e) => println!("Invalid pattern for filter rule {}: {}", name, e),
+        }
+    }
+
+    // one word or phrase per line, blank lines and lines starting with `#` are skipped
+    fn add_wordlist(&mut self, path: &str, action: FilterAction) {
+        let words = match std::fs::read_to_string(path) {
+            Ok(words) => words,
+            Err(e) => {
+                println!("Failed to read filter word list {}: {}", path, e);
+                return;
+            }
+        };
+
+        let words: Vec<String> = words
+            .lines()
+            .map(str::trim)
+            .filter(|word| !word.is_empty() && !word.starts_with('#'))
+            .map(regex::escape)
+            .collect();
+
+        if words.is_empty() {
+            return;
+        }
+
+        self.add(
+            "wordlist",
+            &format!(r"(?i)\b(?:{})\b", words.join("|")),
+            action,
+        );
+    }
+
+    fn add_rules_file(&mut self, path: &str) {
+        let rules: Vec<RuleConfig> = match std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|rules| serde_json::from_str(&rules).map_err(|e| e.to_string()))
+        {
+            Ok(rules) => rules,
+            Err(e) => {
+                println!("Failed to load filter rules {}: {}", path, e);
+                return;
+            }
+        };
+
+        for rule in rules {
+            self.add(&rule.name, &rule.pattern, rule.action);
+        }
+    }
+
+    // stops at the first rule that rejects the message
+    pub fn apply(&self, message: &str) -> Filtered {
+        let mut message = message.to_string();
+        let mut hits = Vec::new();
+
+        for rule in &self.rules {
+            if !rule.pattern.is_match(&message) {
+                continue;
+            }
+
+            hits.push(FilterHit {
+                rule: rule.name.clone(),
+                action: rule.action,
+            });
+
+            match rule.action {
+                FilterAction::Allow => {}
+                FilterAction::Redact => {
+                    message = rule
+                        .pattern
+                        .replace_all(&message, Self::REDACTED)
+                        .into_owned();
+                }
+                FilterAction::Reject => break,
+            }
+        }
+
+        Filtered { message, hits }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(rules: &[(&str, &str, FilterAction)]) -> FilterChain {
+        let mut chain = FilterChain { rules: Vec::new() };
+        for (name, pattern, action) in rules {
+            chain.add(name, pattern, *action);
+        }
+
+        chain
+    }
+
+    // the built in rules, all set to redact so every hit shows in the message
+    fn builtin() -> FilterChain {
+        chain(&[
+            ("email", FilterChain::EMAIL, FilterAction::Redact),
+            ("link", FilterChain::LINK, FilterAction::Redact),
+            (
+                "phone-number",
+                FilterChain::PHONE_NUMBER,
+                FilterAction::Redact,
+            ),
+        ])
+    }
+
+    fn rules(filtered: &Filtered) -> Vec<&str> {
+        filtered.hits.iter().map(|hit| hit.rule.as_str()).collect()
+    }
+
+    #[test]
+    fn redacts_what_matched() {
+        let filtered = builtin()
+            .apply("mail bob.x@gmail.com, see https://scam.io/x or call (416) 555-1234 for $120");
+
+        assert_eq!(
+            filtered.message,
+            "mail [removed], see [removed] or call [removed] for $120"
+        );
+        assert_eq!(rules(&filtered), ["email", "link", "phone-number"]);
+        assert!(!filtered.rejected());
+    }
+
+    #[test]
+    fn allow_only_logs() {
+        let filtered = chain(&[("link", FilterChain::LINK, FilterAction::Allow)])
+            .apply("it's on www.example.com");
+
+        assert_eq!(filtered.message, "it's on www.example.com");
+        assert_eq!(rules(&filtered), ["link"]);
+        assert!(!filtered.rejected());
+    }
+
+    #[test]
+    fn reject_stops_the_chain() {
+        let filtered = chain(&[
+            ("gift-cards", r"(?i)gift ?cards?", FilterAction::Reject),
+            ("link", FilterChain::LINK, FilterAction::Redact),
+        ])
+        .apply("pay me in gift cards at scam.io");
+
+        assert!(filtered.rejected());
+        assert_eq!(rules(&filtered), ["gift-cards"]);
+    }
+
+    #[test]
+    fn rules_run_in_order() {
+        // a redacted email is gone before the link rule sees its domain
+        let filtered = builtin().apply("bob@gmail.com");
+        assert_eq!(filtered.message, "[removed]");
+        assert_eq!(rules(&filtered), ["email"]);
+
+        // with the link rule first the domain is taken for a link
+        let filtered = chain(&[
+            ("link", FilterChain::LINK, FilterAction::Redact),
+            ("email", FilterChain::EMAIL, FilterAction::Redact),
+        ])
+        .apply("bob@gmail.com");
+        assert_eq!(rules(&filtered), ["link"]);
+    }
+
+    #[test]
+    fn ordinary_messages_are_left_alone() {
+        for message in [
+            "ISBN 0306406152",
+            "ISBN 978-0306406157",
+            "ISBN 9780306406157",
+            "Thanks.Me and you can meet at 3.30",
+            "Selling for $120.50, pick up at 350 Victoria St",
+            "I can do 2 p.m. on the 14th",
+        ] {
+            let filtered = builtin().apply(message);
+            assert_eq!(filtered.message, message);
+            assert!(
+                filtered.hits.is_empty(),
+                "{message:?} tripped {:?}",
+                rules(&filtered)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_skipped() {
+        let chain = chain(&[("broken", r"(unclosed", FilterAction::Reject)]);
+        assert!(chain.rules.is_empty());
+    }
+}
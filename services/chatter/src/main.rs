@@ -1,23 +1,43 @@
 #![feature(try_blocks)]
 
+use std::net::{IpAddr, SocketAddr};
+
 use axum::{
-    extract::{State, WebSocketUpgrade},
+    extract::{ConnectInfo, Query, State, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
-use messages::{export_types, ClientMessage};
+use messages::{export_types, ClientRequest, ServerErrors, ServerMessage};
+use serde::Deserialize;
+use uuid::Uuid;
+use ws::leak;
 
+pub mod auth;
+pub mod blocks;
+pub mod config;
+pub mod filter;
 pub mod history;
 pub mod manager;
 pub mod messages;
+pub mod moderation;
+pub mod outbound;
+pub mod ratelimit;
+pub mod typing;
+pub mod validation;
 pub mod ws;
 
 const PORT: u16 = 3001;
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub room: &'static ws::WsPool<ClientMessage>,
+    pub room: &'static ws::WsPool<ClientRequest>,
+    pub auth: &'static auth::Authenticator,
+    pub manager: &'static manager::ChatManager,
+    // use the client address set by the reverse proxy in front of us
+    pub trust_forwarded_for: bool,
 }
 
 #[tokio::main]
@@ -25,29 +45,177 @@ async fn main() {
     #[cfg(debug_assertions)]
     export_types();
 
-    let (room, rx) = ws::WsPool::new();
-    manager::ChatManager::new(room).await.start(rx);
+    let (room, shards, presence) = ws::WsPool::new(ws::PoolConfig::from_env());
+    let auth = leak(auth::Authenticator::from_env());
+    let manager = manager::ChatManager::new(room, auth).await;
+    manager.start(shards, presence);
 
-    let aps = AppState { room };
+    let aps = AppState {
+        room,
+        auth,
+        manager,
+        trust_forwarded_for: config::env_or("TRUST_FORWARDED_FOR", false),
+    };
     let app = Router::new()
         .route("/", get(root))
         .route("/ws", get(ws_handler))
+        .route("/metrics", get(metrics))
+        .route("/admin/announcements", post(announce))
         .with_state(aps);
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{PORT}"))
         .await
-        .expect(&format!("Failed to bind to port {PORT}"));
+        .unwrap_or_else(|_| panic!("Failed to bind to port {PORT}"));
 
     println!("[CHATTER] Listening on port {PORT}");
-    axum::serve(listener, app)
-        .await
-        .expect("Failed to start server");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .expect("Failed to start server");
 }
 
 async fn root() -> impl IntoResponse {
     "Hello, World!"
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, State(app): State<AppState>) -> Response {
-    ws.on_upgrade(move |socket| app.room.add_connection(socket))
+async fn metrics(State(app): State<AppState>) -> impl IntoResponse {
+    Json(app.room.stats())
+}
+
+#[derive(Deserialize)]
+struct AnnouncementRequest {
+    message: String,
+    // everyone if not set
+    users: Option<Vec<Uuid>>,
+}
+
+// posts a system announcement, only for admins
+async fn announce(
+    headers: HeaderMap,
+    State(app): State<AppState>,
+    Json(request): Json<AnnouncementRequest>,
+) -> Response {
+    let Some(token) = auth::bearer_token(&headers) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let Ok(token) = app.auth.verify_token(&token) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let admin = token.user_id;
+    match app
+        .manager
+        .check_session(&admin, Some(token.issued_at))
+        .await
+    {
+        Ok(()) => {}
+        Err(ServerErrors::Internal) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Err(_) => return StatusCode::UNAUTHORIZED.into_response(),
+    }
+
+    match app.manager.require_admin(&admin).await {
+        Ok(()) => {}
+        Err(ServerErrors::Forbidden) => return StatusCode::FORBIDDEN.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+
+    match app.manager.announce(&request.message, request.users).await {
+        Ok(announced) => {
+            println!(
+                "Admin {} announced to {} conversations and {} online users",
+                admin, announced.conversations, announced.delivered
+            );
+
+            Json(announced).into_response()
+        }
+        Err(ServerErrors::Internal) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct UpgradeParams {
+    token: Option<String>,
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<UpgradeParams>,
+    headers: HeaderMap,
+    State(app): State<AppState>,
+) -> Response {
+    // clients that send a token are authenticated before the upgrade,
+    // the others have to authenticate over the socket
+    let cookie_allowed = app.auth.cookie_allowed(&headers);
+    let token = match auth::request_token(&headers, params.token, cookie_allowed) {
+        Some(token) => match app.auth.verify_token(&token) {
+            Ok(token) => Some(token),
+            Err(_) => return StatusCode::UNAUTHORIZED.into_response(),
+        },
+        None => None,
+    };
+
+    if let Some(token) = &token {
+        match app
+            .manager
+            .check_session(&token.user_id, Some(token.issued_at))
+            .await
+        {
+            Ok(()) => {}
+            Err(ServerErrors::Banned) => return StatusCode::FORBIDDEN.into_response(),
+            Err(ServerErrors::Internal) => {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Err(_) => return StatusCode::UNAUTHORIZED.into_response(),
+        }
+    }
+
+    let user_id = token.map(|token| token.user_id);
+
+    let ip = client_ip(&app, addr, &headers);
+    let admission = match user_id {
+        Some(_) => None,
+        None => match app.room.admit(ip) {
+            Some(admission) => Some(admission),
+            None => {
+                println!(
+                    "Refusing connection from {}: too many unauthenticated sockets",
+                    ip
+                );
+                return StatusCode::TOO_MANY_REQUESTS.into_response();
+            }
+        },
+    };
+
+    // nothing a client sends comes close to this, refuse huge frames before parsing them
+    ws.max_message_size(MAX_MESSAGE_SIZE)
+        .on_upgrade(move |socket| async move {
+            let socket_id = app.room.add_connection(socket, admission, user_id).await;
+
+            if let Some(user_id) = user_id {
+                println!("Authenticated: {}", user_id);
+                let message = ServerMessage::Authenticated;
+                if let Err(e) = app.room.send_to_socket(socket_id, message).await {
+                    println!("Failed to send authenticated: {}", e);
+                }
+            }
+        })
+}
+
+fn client_ip(app: &AppState, addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    if !app.trust_forwarded_for {
+        return addr.ip();
+    }
+
+    // the proxy appends the address it saw, so the last entry is the one we can trust
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .unwrap_or(addr.ip())
 }
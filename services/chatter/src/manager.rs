@@ -1,29 +1,79 @@
+use chrono::{DateTime, Utc};
 use futures::{channel::mpsc::UnboundedReceiver, StreamExt};
 use moka::future::Cache;
-use sqlx::Row;
-use std::{collections::HashSet, sync::Arc};
+use serde::Serialize;
+use sqlx::{
+    postgres::{PgListener, PgRow},
+    Row,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::RwLock;
 
 use uuid::Uuid;
 
 use crate::{
+    auth::{self, Authenticator, Lockout},
+    blocks::BlockManager,
+    config,
+    filter::FilterChain,
     history::History,
-    messages::{ChatMessage, ChatUser, ClientMessage, ServerErrors, ServerMessage},
-    ws::{leak, SocketId, TaggedMessage, WsPool},
+    messages::{
+        AdminCommand, ChatMessage, ChatUser, ClientMessage, ClientRequest, MessageId, Report,
+        ReportAction, Role, ServerErrors, ServerMessage, TypingState,
+    },
+    moderation::ModerationManager,
+    ratelimit::{RateLimited, RateLimiter, RateLimits},
+    typing::TypingTracker,
+    validation::Validator,
+    ws::{leak, PresenceEvent, Shard, SocketId, TaggedMessage, WsPool},
 };
 
+pub struct MessagePage {
+    pub messages: Vec<ChatMessage>,
+    pub cursor: Option<MessageId>,
+    pub has_more: bool,
+}
+
+// a message and the conversation around it
+pub struct MessageContext {
+    pub participants: (Uuid, Uuid),
+    pub sender: Uuid,
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Serialize)]
+pub struct Announced {
+    pub conversations: usize,
+    // online users without conversations, who were shown the announcement on its own
+    pub delivered: usize,
+}
+
+pub struct OpenChat {
+    pub with: Uuid,
+    pub unread: u32,
+}
+
 pub struct HistoryManager {
+    dbpool: sqlx::PgPool,
     message_history: Cache<String, Arc<RwLock<History<ChatMessage>>>>,
-    open_chats: Cache<String, Arc<RwLock<HashSet<ChatUser>>>>,
 }
 
 impl HistoryManager {
     const HISTORY_SIZE: usize = 100;
+    const MAX_PAGE_SIZE: usize = 100;
 
-    pub fn new() -> Self {
+    pub fn new(dbpool: sqlx::PgPool) -> Self {
         Self {
-            message_history: Cache::builder().build(),
-            open_chats: Cache::builder().build(),
+            dbpool,
+            // postgres has every message, the buffers only keep active conversations
+            message_history: Cache::builder()
+                .max_capacity(config::env_or("HISTORY_CACHE_CONVERSATIONS", 10_000))
+                .time_to_idle(config::env_secs_or("HISTORY_CACHE_IDLE_SECS", 30 * 60))
+                .build(),
         }
     }
 
@@ -35,312 +85,1546 @@ impl HistoryManager {
         }
     }
 
-    pub async fn get_history(&self, history_id: &str) -> Arc<RwLock<History<ChatMessage>>> {
+    // participants in the order they are stored in the database
+    fn participants<'a>(a: &'a Uuid, b: &'a Uuid) -> (&'a Uuid, &'a Uuid) {
+        if a < b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    // the ring buffer is a hot cache in front of the messages table,
+    // a cold conversation is loaded from the database on first access
+    pub async fn get_history(
+        &self,
+        a: &Uuid,
+        b: &Uuid,
+    ) -> Result<Arc<RwLock<History<ChatMessage>>>, Arc<sqlx::Error>> {
         self.message_history
-            .get_with_by_ref(history_id, async {
-                Arc::new(RwLock::new(History::new(Self::HISTORY_SIZE)))
+            .try_get_with_by_ref(&Self::history_id(a, b), async {
+                let mut history = History::new(Self::HISTORY_SIZE);
+                let page = self.load_page(a, b, None, Self::HISTORY_SIZE).await?;
+                for message in page.messages {
+                    history.push(message);
+                }
+
+                Ok(Arc::new(RwLock::new(history)))
             })
             .await
     }
 
-    pub async fn get_open_chats(&self, user: &str) -> Arc<RwLock<HashSet<ChatUser>>> {
-        self.open_chats
-            .get_with_by_ref(user, async { Arc::new(RwLock::new(HashSet::new())) })
-            .await
+    // every conversation the user is part of, most recently active first
+    pub async fn get_open_chats(&self, user: &Uuid) -> Vec<OpenChat> {
+        let rows = sqlx::query(
+            "SELECT CASE WHEN c.user_a = $1 THEN c.user_b ELSE c.user_a END AS partner, \
+             (SELECT COUNT(*) FROM messages m \
+              WHERE m.user_a = c.user_a AND m.user_b = c.user_b \
+              AND m.kind = 'user' AND m.sender <> $1 \
+              AND m.seq > CASE WHEN c.user_a = $1 THEN c.user_a_read_seq ELSE c.user_b_read_seq END \
+             ) AS unread \
+             FROM conversations c WHERE c.user_a = $1 OR c.user_b = $1 \
+             ORDER BY c.last_message_at DESC",
+        )
+        .bind(user)
+        .fetch_all(&self.dbpool)
+        .await;
+
+        match rows {
+            Ok(rows) => rows
+                .iter()
+                .filter_map(|row| {
+                    Some(OpenChat {
+                        with: row.try_get("partner").ok()?,
+                        unread: row.try_get::<i64, _>("unread").ok()? as u32,
+                    })
+                })
+                .collect(),
+            Err(e) => {
+                println!("Failed to load open chats: {}", e);
+                Vec::new()
+            }
+        }
     }
 
-    pub async fn open_chat(&self, user: ChatUser, with: ChatUser) {
-        let user_chats = self.get_open_chats(&user.id).await;
-        let with_chats = self.get_open_chats(&with.id).await;
-        user_chats.write().await.insert(with);
-        with_chats.write().await.insert(user);
+    // moves the read cursor of `reader` forward to `up_to`,
+    // returns false if the message is not part of the conversation
+    pub async fn mark_read(
+        &self,
+        reader: &Uuid,
+        with: &Uuid,
+        up_to: &Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let (user_a, user_b) = Self::participants(reader, with);
+        let result = sqlx::query(
+            "UPDATE conversations c SET \
+             user_a_read_seq = CASE WHEN c.user_a = $3 \
+                THEN GREATEST(c.user_a_read_seq, m.seq) ELSE c.user_a_read_seq END, \
+             user_b_read_seq = CASE WHEN c.user_b = $3 \
+                THEN GREATEST(c.user_b_read_seq, m.seq) ELSE c.user_b_read_seq END \
+             FROM messages m \
+             WHERE c.user_a = $1 AND c.user_b = $2 \
+             AND m.id = $4 AND m.user_a = $1 AND m.user_b = $2",
+        )
+        .bind(user_a)
+        .bind(user_b)
+        .bind(reader)
+        .bind(up_to)
+        .execute(&self.dbpool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn push_message(&self, from: &Uuid, to: &Uuid, message: ChatMessage) {
-        let history_id = Self::history_id(from, to);
-        self.get_history(&history_id)
-            .await
-            .write()
-            .await
-            .push(message);
+    // stores a server message in every conversation one of the users is part of
+    // (every conversation if `None`) with a single statement, only the ring buffers
+    // already in the cache get it so cold conversations don't push out hot ones
+    pub async fn store_announcement(
+        &self,
+        message: &str,
+        users: Option<&[Uuid]>,
+    ) -> Result<Vec<(Uuid, Uuid, ChatMessage)>, sqlx::Error> {
+        let sent_at = Utc::now();
+        let rows = sqlx::query(
+            "INSERT INTO messages (id, user_a, user_b, kind, body, created_at) \
+             SELECT uuid_generate_v7(), user_a, user_b, 'server', $2, $3 FROM conversations \
+             WHERE $1::UUID[] IS NULL OR user_a = ANY($1) OR user_b = ANY($1) \
+             RETURNING id, user_a, user_b",
+        )
+        .bind(users)
+        .bind(message)
+        .bind(sent_at)
+        .fetch_all(&self.dbpool)
+        .await?;
+
+        let mut stored = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id: Uuid = row.try_get("id")?;
+            let (a, b) = (row.try_get("user_a")?, row.try_get("user_b")?);
+            let message = ChatMessage::Server {
+                id: id.to_string(),
+                sent_at,
+                message: message.to_string(),
+            };
+
+            // a buffer that is still loading may miss it, pages are read from the table
+            if let Some(history) = self.message_history.get(&Self::history_id(&a, &b)).await {
+                let mut history = history.write().await;
+                if !history.iter().any(|m| m.id() == message.id()) {
+                    history.push(message.clone());
+                }
+            }
+
+            stored.push((a, b, message));
+        }
+
+        Ok(stored)
+    }
+
+    // the other participant of every conversation the user is part of
+    pub async fn get_chat_partners(&self, user: &Uuid) -> Vec<Uuid> {
+        let rows = sqlx::query(
+            "SELECT CASE WHEN user_a = $1 THEN user_b ELSE user_a END AS partner \
+             FROM conversations WHERE user_a = $1 OR user_b = $1",
+        )
+        .bind(user)
+        .fetch_all(&self.dbpool)
+        .await;
+
+        match rows {
+            Ok(rows) => rows
+                .iter()
+                .filter_map(|row| row.try_get::<Uuid, _>("partner").ok())
+                .collect(),
+            Err(e) => {
+                println!("Failed to load chat partners: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    // opens the conversation if needed and marks it as the most recently active
+    pub async fn open_chat(&self, user: &Uuid, with: &Uuid) {
+        let (user_a, user_b) = Self::participants(user, with);
+        let result = sqlx::query(
+            "INSERT INTO conversations (user_a, user_b) VALUES ($1, $2) \
+             ON CONFLICT (user_a, user_b) DO UPDATE SET last_message_at = CURRENT_TIMESTAMP",
+        )
+        .bind(user_a)
+        .bind(user_b)
+        .execute(&self.dbpool)
+        .await;
+
+        if let Err(e) = result {
+            println!("Failed to open chat: {}", e);
+        }
+    }
+
+    // nothing is cached when the message could not be stored
+    pub async fn push_message(
+        &self,
+        from: &Uuid,
+        to: &Uuid,
+        message: ChatMessage,
+    ) -> Result<(), sqlx::Error> {
+        self.store_message(from, to, &message).await?;
+
+        // waits for a load that is already running, it may have read the table
+        // before the message was stored or after, so it can be there already
+        match self.get_history(from, to).await {
+            Ok(history) => {
+                let mut history = history.write().await;
+                if !history.iter().any(|m| m.id() == message.id()) {
+                    history.push(message);
+                }
+            }
+            Err(e) => println!("Failed to load chat history: {}", e),
+        }
+
+        Ok(())
     }
 
     pub async fn get_messages(&self, from: &Uuid, to: &Uuid) -> Vec<ChatMessage> {
-        let history_id = Self::history_id(from, to);
-        self.get_history(&history_id).await.read().await.to_vec()
+        match self.get_history(from, to).await {
+            Ok(history) => history.read().await.to_vec(),
+            Err(e) => {
+                println!("Failed to load chat history: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn store_message(
+        &self,
+        from: &Uuid,
+        to: &Uuid,
+        message: &ChatMessage,
+    ) -> Result<(), sqlx::Error> {
+        let (user_a, user_b) = Self::participants(from, to);
+        let (kind, sender, body) = match message {
+            ChatMessage::User { from, message, .. } => {
+                ("user", Uuid::parse_str(from).ok(), message)
+            }
+            ChatMessage::Topic { topic, .. } => ("topic", None, topic),
+            ChatMessage::Server { message, .. } => ("server", None, message),
+        };
+
+        let id = Uuid::parse_str(message.id()).map_err(|e| sqlx::Error::Decode(e.into()))?;
+
+        sqlx::query(
+            "INSERT INTO messages (id, user_a, user_b, kind, sender, body, created_at) \
+             VALUES ($1, $2, $3, $4::chat_message_kind, $5, $6, $7)",
+        )
+        .bind(id)
+        .bind(user_a)
+        .bind(user_b)
+        .bind(kind)
+        .bind(sender)
+        .bind(body)
+        .bind(message.sent_at())
+        .execute(&self.dbpool)
+        .await?;
+
+        Ok(())
+    }
+
+    // pages always come from the database, so scroll-back is not limited by the ring buffer
+    pub async fn get_page(
+        &self,
+        a: &Uuid,
+        b: &Uuid,
+        before: Option<Uuid>,
+        limit: usize,
+    ) -> Result<MessagePage, sqlx::Error> {
+        self.load_page(a, b, before, limit.clamp(1, Self::MAX_PAGE_SIZE))
+            .await
+    }
+
+    // loads up to `limit` messages older than the message `before`, oldest first
+    async fn load_page(
+        &self,
+        a: &Uuid,
+        b: &Uuid,
+        before: Option<Uuid>,
+        limit: usize,
+    ) -> Result<MessagePage, sqlx::Error> {
+        let (user_a, user_b) = Self::participants(a, b);
+        let mut rows = sqlx::query(
+            "SELECT id, kind::text AS kind, sender, body, created_at FROM messages \
+             WHERE user_a = $1 AND user_b = $2 \
+             AND ($3::UUID IS NULL OR seq < (SELECT seq FROM messages WHERE id = $3)) \
+             ORDER BY seq DESC LIMIT $4",
+        )
+        .bind(user_a)
+        .bind(user_b)
+        .bind(before)
+        .bind(limit as i64 + 1)
+        .fetch_all(&self.dbpool)
+        .await?;
+
+        // the extra row only tells us whether there is another page
+        let has_more = rows.len() > limit;
+        rows.truncate(limit);
+
+        let cursor = match rows.last() {
+            Some(row) => Some(row.try_get::<Uuid, _>("id")?.to_string()),
+            None => None,
+        };
+
+        let messages = rows
+            .iter()
+            .rev()
+            .map(Self::message_from_row)
+            .collect::<Result<_, _>>()?;
+
+        Ok(MessagePage {
+            messages,
+            cursor,
+            has_more,
+        })
+    }
+
+    // the user message with the id and up to `around` messages on either side of it
+    pub async fn get_context(
+        &self,
+        message_id: &Uuid,
+        around: usize,
+    ) -> Result<Option<MessageContext>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT user_a, user_b, sender, seq FROM messages WHERE id = $1 AND kind = 'user'",
+        )
+        .bind(message_id)
+        .fetch_optional(&self.dbpool)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let user_a: Uuid = row.try_get("user_a")?;
+        let user_b: Uuid = row.try_get("user_b")?;
+        let Some(sender) = row.try_get::<Option<Uuid>, _>("sender")? else {
+            return Ok(None);
+        };
+
+        let seq: i64 = row.try_get("seq")?;
+        let rows = sqlx::query(
+            "SELECT * FROM ( \
+              (SELECT seq, id, kind::text AS kind, sender, body, created_at FROM messages \
+               WHERE user_a = $1 AND user_b = $2 AND seq <= $3 ORDER BY seq DESC LIMIT $4) \
+              UNION ALL \
+              (SELECT seq, id, kind::text AS kind, sender, body, created_at FROM messages \
+               WHERE user_a = $1 AND user_b = $2 AND seq > $3 ORDER BY seq ASC LIMIT $5) \
+             ) around ORDER BY seq",
+        )
+        .bind(user_a)
+        .bind(user_b)
+        .bind(seq)
+        .bind(around as i64 + 1)
+        .bind(around as i64)
+        .fetch_all(&self.dbpool)
+        .await?;
+
+        let messages = rows
+            .iter()
+            .map(Self::message_from_row)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Some(MessageContext {
+            participants: (user_a, user_b),
+            sender,
+            messages,
+        }))
+    }
+
+    fn message_from_row(row: &PgRow) -> Result<ChatMessage, sqlx::Error> {
+        let id = row.try_get::<Uuid, _>("id")?.to_string();
+        let sent_at: DateTime<Utc> = row.try_get("created_at")?;
+        let kind: String = row.try_get("kind")?;
+        let body: String = row.try_get("body")?;
+
+        let message = match kind.as_str() {
+            "user" => ChatMessage::User {
+                id,
+                sent_at,
+                from: row
+                    .try_get::<Option<Uuid>, _>("sender")?
+                    .map(|s| s.to_string())
+                    .unwrap_or_default(),
+                message: body,
+            },
+            "topic" => ChatMessage::Topic {
+                id,
+                sent_at,
+                topic: body,
+            },
+            _ => ChatMessage::Server {
+                id,
+                sent_at,
+                message: body,
+            },
+        };
+
+        Ok(message)
     }
 }
 
 pub struct ChatManager {
     pub metadata: Cache<Uuid, ChatUser>,
     pub dbpool: sqlx::PgPool,
-    pub wspool: &'static WsPool<ClientMessage>,
+    pub wspool: &'static WsPool<ClientRequest>,
     pub history: HistoryManager,
+    pub blocks: BlockManager,
+    pub moderation: ModerationManager,
+    pub typing: TypingTracker,
+    pub limiter: RateLimiter,
+    pub validator: Validator,
+    pub filters: FilterChain,
+    pub auth: &'static Authenticator,
+    pub lockout: Lockout,
 }
 
 impl ChatManager {
-    pub async fn new(wsroom: &'static WsPool<ClientMessage>) -> &'static Self {
+    const MAX_PRESENCE_QUERY: usize = 100;
+    const SESSION_REVOKED: u16 = 4003;
+    const DISCONNECTED_BY_ADMIN: u16 = 4004;
+    // messages kept on either side of a reported message
+    const REPORT_CONTEXT: usize = 10;
+    const MAX_MUTE_MINUTES: u32 = 60 * 24 * 365;
+    // websocket close code for sockets that broke the rules
+    const POLICY_VIOLATION: u16 = 1008;
+
+    pub async fn new(
+        wsroom: &'static WsPool<ClientRequest>,
+        auth: &'static Authenticator,
+    ) -> &'static Self {
         let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
         let pool = sqlx::PgPool::connect(&database_url)
             .await
             .expect("Failed to connect to database");
 
         leak(Self {
-            metadata: Cache::builder().build(),
+            // refreshed now and then so role changes show up, authorization
+            // never relies on it
+            metadata: Cache::builder()
+                .time_to_live(config::env_secs_or("USER_METADATA_TTL_SECS", 5 * 60))
+                .build(),
+            history: HistoryManager::new(pool.clone()),
+            blocks: BlockManager::new(pool.clone()),
+            moderation: ModerationManager::new(pool.clone()),
             dbpool: pool,
             wspool: wsroom,
-            history: HistoryManager::new(),
+            typing: TypingTracker::new(),
+            limiter: RateLimiter::new(RateLimits::from_env(&ClientMessage::KINDS)),
+            validator: Validator::from_env(),
+            filters: FilterChain::from_env(),
+            auth,
+            lockout: Lockout::from_env(),
         })
     }
 
-    async fn set_topic(&self, from: Uuid, to: Uuid, topic: String) {
+    // users can only message someone else who has an account
+    async fn validate_recipient(&self, from: &Uuid, to: &Uuid) -> Result<(), ServerErrors> {
+        if from == to {
+            return Err(ServerErrors::InvalidUser);
+        }
+
+        self.find_user(to).await.ok_or(ServerErrors::InvalidUser)?;
+
+        if self.blocks.between(from, to).await {
+            return Err(ServerErrors::Blocked);
+        }
+
+        Ok(())
+    }
+
+    async fn check_muted(&self, user: &Uuid) -> Result<(), ServerErrors> {
+        let until = self.moderation.muted_until(user).await.map_err(|e| {
+            println!("Failed to check if user is muted: {}", e);
+            ServerErrors::Internal
+        })?;
+
+        match until {
+            Some(until) => Err(ServerErrors::Muted { until }),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn require_admin(&self, user: &Uuid) -> Result<(), ServerErrors> {
+        match self.moderation.is_admin(user).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ServerErrors::Forbidden),
+            Err(e) => {
+                println!("Failed to check user role: {}", e);
+                Err(ServerErrors::Internal)
+            }
+        }
+    }
+
+    // posts a server message into every conversation of the users (everyone if `None`)
+    // and shows it to those of them that are online
+    pub async fn announce(
+        &'static self,
+        message: &str,
+        users: Option<Vec<Uuid>>,
+    ) -> Result<Announced, ServerErrors> {
+        let message = self.validator.message(message)?;
+
+        let conversations = self
+            .history
+            .store_announcement(&message, users.as_deref())
+            .await
+            .map_err(|e| {
+                println!("Failed to store announcement: {}", e);
+                ServerErrors::Internal
+            })?;
+
+        // every conversation gets its own copy, ids are unique across conversations
+        for (a, b, chat_message) in &conversations {
+            let direct = ServerMessage::DirectMessage {
+                participants: [a.to_string(), b.to_string()],
+                message: chat_message.clone(),
+            };
+            if let Err(e) = self.wspool.send_to_users(&[*a, *b], direct).await {
+                println!("Failed to send announcement: {}", e);
+            }
+        }
+
+        // users without conversations only see it while they are online
+        let in_conversation: HashSet<Uuid> =
+            conversations.iter().flat_map(|&(a, b, _)| [a, b]).collect();
+        let announcement = ServerMessage::Announcement {
+            message: ChatMessage::server(message),
+        };
+        let delivered = self
+            .wspool
+            .broadcast(announcement, |user| {
+                let targeted = match &users {
+                    Some(users) => users.contains(user),
+                    None => true,
+                };
+
+                targeted && !in_conversation.contains(user)
+            })
+            .await
+            .map_err(|e| {
+                println!("Failed to broadcast announcement: {}", e);
+                ServerErrors::Internal
+            })?;
+
+        Ok(Announced {
+            conversations: conversations.len(),
+            delivered,
+        })
+    }
+
+    fn mute_duration(minutes: u32) -> Result<chrono::Duration, ServerErrors> {
+        if minutes == 0 || minutes > Self::MAX_MUTE_MINUTES {
+            return Err(ServerErrors::InvalidMessage);
+        }
+
+        Ok(chrono::Duration::minutes(minutes.into()))
+    }
+
+    async fn mute_user(&'static self, user: Uuid, minutes: u32) -> Result<(), ServerErrors> {
+        let until = Utc::now() + Self::mute_duration(minutes)?;
+        if let Err(e) = self.moderation.mute(&user, until).await {
+            println!("Failed to mute user: {}", e);
+            return Err(ServerErrors::Internal);
+        }
+
+        let message = ServerMessage::ModerationNotice {
+            action: ReportAction::Mute { minutes },
+        };
+        if let Err(e) = self.wspool.send_to_user(user, message).await {
+            println!("Failed to send moderation notice: {}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn report_message(
+        &self,
+        reporter: Uuid,
+        message_id: Uuid,
+        reason: String,
+    ) -> Result<(), ServerErrors> {
+        let reason = self.validator.reason(&reason)?;
+
+        let context = self
+            .history
+            .get_context(&message_id, Self::REPORT_CONTEXT)
+            .await
+            .map_err(|e| {
+                println!("Failed to load reported message: {}", e);
+                ServerErrors::Internal
+            })?
+            .ok_or(ServerErrors::InvalidMessage)?;
+
+        // only messages someone else sent to the reporter can be reported
+        let (user_a, user_b) = context.participants;
+        if (reporter != user_a && reporter != user_b) || context.sender == reporter {
+            return Err(ServerErrors::InvalidMessage);
+        }
+
+        let reported = self
+            .moderation
+            .report(
+                &reporter,
+                &context.sender,
+                &message_id,
+                &reason,
+                &context.messages,
+            )
+            .await
+            .map_err(|e| {
+                println!("Failed to store report: {}", e);
+                ServerErrors::Internal
+            })?;
+
+        if reported {
+            println!("Message {} reported by {}", message_id, reporter);
+        }
+
+        Ok(())
+    }
+
+    async fn send_reports(&self, socket_id: SocketId) -> Result<(), ServerErrors> {
+        let stored = self.moderation.open_reports().await.map_err(|e| {
+            println!("Failed to load reports: {}", e);
+            ServerErrors::Internal
+        })?;
+
+        let mut reports = Vec::with_capacity(stored.len());
+        for report in stored {
+            reports.push(Report {
+                id: report.id.to_string(),
+                reporter: self.get_user_metadata(&report.reporter).await,
+                reported: self.get_user_metadata(&report.reported).await,
+                message_id: report.message_id.to_string(),
+                reason: report.reason,
+                context: report.context,
+                created_at: report.created_at,
+            });
+        }
+
+        let message = ServerMessage::Reports { reports };
+        if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+            println!("Failed to send reports: {}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn resolve_report(
+        &'static self,
+        admin: Uuid,
+        report_id: Uuid,
+        action: ReportAction,
+    ) -> Result<(), ServerErrors> {
+        // checked before anything is written
+        let action = match action {
+            ReportAction::Warn { message } => ReportAction::Warn {
+                message: self.validator.message(&message)?,
+            },
+            ReportAction::Mute { minutes } => {
+                Self::mute_duration(minutes)?;
+                ReportAction::Mute { minutes }
+            }
+            action => action,
+        };
+
+        let reported = self
+            .moderation
+            .resolve(&report_id, &admin, &action)
+            .await
+            .map_err(|e| {
+                println!("Failed to resolve report: {}", e);
+                ServerErrors::Internal
+            })?
+            .ok_or(ServerErrors::InvalidMessage)?;
+
+        println!("Report {} resolved by {}: {:?}", report_id, admin, action);
+
+        if let ReportAction::Dismiss = action {
+            return Ok(());
+        }
+
+        let message = ServerMessage::ModerationNotice { action };
+        if let Err(e) = self.wspool.send_to_user(reported, message).await {
+            println!("Failed to send moderation notice: {}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn admin(
+        &'static self,
+        socket_id: SocketId,
+        admin: Uuid,
+        command: AdminCommand,
+    ) -> Result<(), ServerErrors> {
+        self.require_admin(&admin).await?;
+
+        match command {
+            AdminCommand::ListReports => self.send_reports(socket_id).await?,
+            AdminCommand::ResolveReport { report_id, action } => {
+                let report_id = parse_uuid(&report_id)?;
+                self.resolve_report(admin, report_id, action).await?;
+            }
+            AdminCommand::ReadChat {
+                between,
+                before,
+                limit,
+            } => {
+                let [a, b] = &between;
+                let (a, b) = (parse_uuid(a)?, parse_uuid(b)?);
+                let before = before.as_deref().map(parse_uuid).transpose()?;
+
+                let page = self
+                    .history
+                    .get_page(&a, &b, before, limit as usize)
+                    .await
+                    .map_err(|e| {
+                        println!("Failed to load history page: {}", e);
+                        ServerErrors::Internal
+                    })?;
+
+                println!("Admin {} read the chat between {} and {}", admin, a, b);
+
+                let message = ServerMessage::HistoryPage {
+                    participants: between,
+                    messages: page.messages,
+                    cursor: page.cursor,
+                    has_more: page.has_more,
+                };
+                if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+                    println!("Failed to send history page: {}", e);
+                }
+            }
+            AdminCommand::Broadcast { message, users } => {
+                let message = self.validator.message(&message)?;
+                let users = users
+                    .map(|users| users.iter().map(|user| parse_uuid(user)).collect())
+                    .transpose()?;
+
+                // walks every conversation, which would hold up the sockets of this shard
+                tokio::spawn(async move {
+                    match self.announce(&message, users).await {
+                        Ok(announced) => println!(
+                            "Admin {} announced to {} conversations and {} online users",
+                            admin, announced.conversations, announced.delivered
+                        ),
+                        Err(e) => println!("Failed to announce: {:?}", e),
+                    }
+                });
+            }
+            AdminCommand::MuteUser { user, minutes } => {
+                let user = parse_uuid(&user)?;
+                self.mute_user(user, minutes).await?;
+                println!("Admin {} muted {} for {} minutes", admin, user, minutes);
+            }
+            AdminCommand::DisconnectUser { user } => {
+                let user = parse_uuid(&user)?;
+                println!("Admin {} disconnected {}", admin, user);
+                self.wspool
+                    .close_user(
+                        user,
+                        Self::DISCONNECTED_BY_ADMIN,
+                        "Disconnected by an admin",
+                    )
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_blocked_users(
+        &self,
+        socket_id: SocketId,
+        user_id: &Uuid,
+    ) -> Result<(), ServerErrors> {
+        let blocked = self.blocks.blocked_by(user_id).await.map_err(|e| {
+            println!("Failed to load blocks: {}", e);
+            ServerErrors::Internal
+        })?;
+
+        let message = ServerMessage::BlockedUsers {
+            users: blocked.iter().map(|user| user.to_string()).collect(),
+        };
+
+        if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+            println!("Failed to send blocked users: {}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn set_topic(&self, from: Uuid, to: Uuid, topic: String) -> Result<(), ServerErrors> {
+        let topic = self.validator.topic(&topic)?;
+        self.check_muted(&from).await?;
+        self.validate_recipient(&from, &to).await?;
+
         let from_str = from.to_string();
         let to_str = to.to_string();
 
-        let cm = ChatMessage::Topic { topic };
+        let cm = ChatMessage::topic(topic);
 
         let message = ServerMessage::DirectMessage {
             participants: [from_str, to_str],
             message: cm.clone(),
         };
 
-        self.history.push_message(&from, &to, cm).await;
+        self.history
+            .push_message(&from, &to, cm)
+            .await
+            .map_err(|e| {
+                println!("Failed to store message: {}", e);
+                ServerErrors::Internal
+            })?;
 
         if let Err(e) = self.wspool.send_to_users(&[from, to], message).await {
             println!("Failed to send message: {}", e);
         }
 
-        // grab metadata for the user
-        let from = self.get_user_metadata(&from).await;
-        let to = self.get_user_metadata(&to).await;
-
-        self.history.open_chat(from, to).await;
+        self.history.open_chat(&from, &to).await;
+        Ok(())
     }
 
-    async fn send_message(&self, from: Uuid, to: Uuid, message: String) {
+    async fn send_message(
+        &self,
+        from: Uuid,
+        to: Uuid,
+        message: String,
+    ) -> Result<(), ServerErrors> {
+        let message = self.validator.message(&message)?;
+        self.check_muted(&from).await?;
+        self.validate_recipient(&from, &to).await?;
+        let message = self.filter_message(&from, &to, message).await?;
+
         let from_str = from.to_string();
         let to_str = to.to_string();
 
-        let cm = ChatMessage::User {
-            from: from_str.clone(),
-            message,
-        };
+        let cm = ChatMessage::user(from_str.clone(), message);
 
         let message = ServerMessage::DirectMessage {
             participants: [from_str, to_str],
             message: cm.clone(),
         };
 
-        self.history.push_message(&from, &to, cm).await;
+        let message_id = cm.id().clone();
+        self.history
+            .push_message(&from, &to, cm)
+            .await
+            .map_err(|e| {
+                println!("Failed to store message: {}", e);
+                ServerErrors::Internal
+            })?;
+
+        // receiving the message clears the typing indicator on the client
+        self.typing.stop(from, to);
 
         if let Err(e) = self.wspool.send_to_users(&[from, to], message).await {
             println!("Failed to send message: {}", e);
         }
 
-        // grab metadata for the user
-        let from = self.get_user_metadata(&from).await;
-        let to = self.get_user_metadata(&to).await;
+        // the message reached at least one live socket of the recipient
+        if self.wspool.is_online(&to) {
+            let message = ServerMessage::Delivered {
+                participants: [from.to_string(), to.to_string()],
+                message_id,
+            };
+
+            if let Err(e) = self.wspool.send_to_user(from, message).await {
+                println!("Failed to send delivery receipt: {}", e);
+            }
+        }
 
-        self.history.open_chat(from, to).await;
+        self.history.open_chat(&from, &to).await;
+        Ok(())
     }
 
-    pub fn start(&'static self, mut rx: UnboundedReceiver<TaggedMessage<ClientMessage>>) {
+    // runs the message through the filter chain, logging what it tripped
+    async fn filter_message(
+        &self,
+        from: &Uuid,
+        to: &Uuid,
+        message: String,
+    ) -> Result<String, ServerErrors> {
+        let filtered = self.filters.apply(&message);
+        if filtered.hits.is_empty() {
+            return Ok(message);
+        }
+
+        let rules: Vec<&str> = filtered.hits.iter().map(|hit| hit.rule.as_str()).collect();
+        println!(
+            "Message from {} tripped filters: {}",
+            from,
+            rules.join(", ")
+        );
+
+        if let Err(e) = self
+            .moderation
+            .log_filter_hits(from, to, &message, &filtered.hits)
+            .await
+        {
+            println!("Failed to log filter hits: {}", e);
+        }
+
+        if filtered.rejected() {
+            return Err(ServerErrors::InvalidMessage);
+        }
+
+        Ok(filtered.message)
+    }
+
+    async fn typing(&'static self, socket_id: SocketId, from: Uuid, to: Uuid, state: TypingState) {
+        match state {
+            TypingState::Started => {
+                // already shown as typing, only push back the expiry
+                if self.typing.refresh(from, to) {
+                    return;
+                }
+
+                if !self.typing.allow(socket_id).await {
+                    return;
+                }
+
+                self.typing.start(from, to);
+                tokio::spawn(self.expire_typing(from, to));
+            }
+            TypingState::Stopped => {
+                // a throttled stop is left to the expiry
+                if !self.typing.allow(socket_id).await {
+                    return;
+                }
+
+                if !self.typing.stop(from, to) {
+                    return;
+                }
+            }
+        }
+
+        self.send_typing(from, to, state).await;
+    }
+
+    async fn expire_typing(&'static self, from: Uuid, to: Uuid) {
+        while let Some(remaining) = self.typing.expires_in(from, to) {
+            if remaining.is_zero() {
+                self.send_typing(from, to, TypingState::Stopped).await;
+                return;
+            }
+
+            tokio::time::sleep(remaining).await;
+        }
+    }
+
+    async fn send_typing(&self, from: Uuid, to: Uuid, state: TypingState) {
+        let message = ServerMessage::Typing {
+            from: from.to_string(),
+            state,
+        };
+
+        if let Err(e) = self.wspool.send_to_user(to, message).await {
+            println!("Failed to send typing state: {}", e);
+        }
+    }
+
+    async fn presence(&self, user_id: Uuid) -> ServerMessage {
+        ServerMessage::Presence {
+            user: user_id.to_string(),
+            online: self.wspool.is_online(&user_id),
+            last_seen: self.wspool.last_seen(&user_id).await,
+        }
+    }
+
+    // tells everyone the user has an open chat with that they came online or went offline
+    async fn publish_presence(&self, event: PresenceEvent) {
+        let (PresenceEvent::Online(user_id) | PresenceEvent::Offline(user_id)) = event;
+
+        let mut partners = Vec::new();
+        for partner in self.history.get_chat_partners(&user_id).await {
+            if !self.blocks.between(&user_id, &partner).await {
+                partners.push(partner);
+            }
+        }
+
+        if partners.is_empty() {
+            return;
+        }
+
+        let message = self.presence(user_id).await;
+        if let Err(e) = self.wspool.send_to_users(&partners, message).await {
+            println!("Failed to send presence: {}", e);
+        }
+    }
+
+    pub fn start(
+        &'static self,
+        shards: Vec<Shard<ClientRequest>>,
+        mut presence: UnboundedReceiver<PresenceEvent>,
+    ) {
         tokio::spawn(async move {
-            while let Some(TaggedMessage {
-                socket_id,
-                user_id,
-                message,
-            }) = rx.next().await
-            {
-                match message {
-                    ClientMessage::DirectMessage { to, message } => {
-                        // If the user is not logged in, we can't do anything
-                        let Some(from) = user_id else {
-                            self.send_error(socket_id, ServerErrors::Unauthorized).await;
-                            continue;
-                        };
-
-                        let Ok(to) = Uuid::parse_str(&to) else {
-                            self.send_error(socket_id, ServerErrors::InvalidUuid).await;
-                            continue;
-                        };
-
-                        self.send_message(from, to, message).await
-                    }
-                    ClientMessage::SyncChat { with } => {
-                        // If the user is not logged in, we can't do anything
-                        let Some(from) = user_id else {
-                            self.send_error(socket_id, ServerErrors::Unauthorized).await;
-                            continue;
-                        };
-
-                        let Ok(with) = Uuid::parse_str(&with) else {
-                            self.send_error(socket_id, ServerErrors::InvalidUuid).await;
-                            continue;
-                        };
-
-                        let messages = self.history.get_messages(&from, &with).await;
-                        let message = ServerMessage::BulkMessages {
-                            participants: [from.to_string(), with.to_string()],
-                            messages,
-                        };
-
-                        if let Err(e) = self.wspool.send_to_user(from, message).await {
-                            println!("Failed to send chat history: {}", e);
-                        }
-                    }
-                    ClientMessage::Ping => {
-                        let message = ServerMessage::Pong;
-                        if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
-                            println!("Failed to send pong: {}", e);
-                        }
-                    }
+            while let Some(event) = presence.next().await {
+                self.publish_presence(event).await;
+            }
+        });
+
+        tokio::spawn(self.listen_revocations());
+
+        // shards are worked on concurrently, each one handles its messages in order
+        for mut rx in shards {
+            tokio::spawn(async move {
+                while let Some(message) = rx.next().await {
+                    self.dispatch(message).await;
+                }
+            });
+        }
+    }
+
+    async fn dispatch(&'static self, message: TaggedMessage<ClientRequest>) {
+        let TaggedMessage {
+            socket_id,
+            user_id,
+            message: ClientRequest { nonce, message },
+        } = message;
 
-                    ClientMessage::Authenticate { id, secret } => {
-                        println!("Authentication Attempt: {}", id);
-                        if let Some(_) = user_id {
-                            self.send_error(socket_id, ServerErrors::AlreadyAuthenticated)
-                                .await;
-                            continue;
-                        }
-
-                        let Ok(id) = Uuid::parse_str(&id) else {
-                            self.send_error(socket_id, ServerErrors::InvalidUuid).await;
-                            continue;
-                        };
-
-                        let Ok(secret) = Uuid::parse_str(&secret) else {
-                            self.send_error(socket_id, ServerErrors::InvalidSecret)
-                                .await;
-                            continue;
-                        };
-
-                        if !self.verify_secret(&id, &secret).await {
-                            self.send_error(socket_id, ServerErrors::InvalidSecret)
-                                .await;
-                            continue;
-                        }
-
-                        self.wspool.authenticate(id, socket_id).await;
-
-                        let message = ServerMessage::Authenticated;
-                        println!("Authenticated: {}", id);
-                        if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
-                            println!("Failed to send authenticated: {}", e);
-                        }
+        // queued before the socket was closed, possibly for a user it no longer speaks for
+        if !self.wspool.is_connected(socket_id) {
+            return;
+        }
+
+        match self.limiter.check(socket_id, user_id, message.kind()).await {
+            Ok(()) => {}
+            Err(RateLimited::Retry(retry_after)) => {
+                let error = ServerErrors::RateLimited {
+                    retry_after_ms: retry_after.as_millis().try_into().unwrap_or(u32::MAX),
+                };
+
+                self.send_error(socket_id, nonce, error).await;
+                return;
+            }
+            Err(RateLimited::Disconnect) => {
+                println!(
+                    "Disconnecting socket {} for going over its rate limits",
+                    socket_id
+                );
+                self.wspool
+                    .close_socket(socket_id, Self::POLICY_VIOLATION, "Rate limited")
+                    .await;
+                return;
+            }
+        }
+
+        let result = self.handle_message(socket_id, user_id, message).await;
+        let failed_auth = matches!(
+            result,
+            Err(ServerErrors::InvalidSecret | ServerErrors::InvalidToken)
+        );
+
+        // the nonce lets the client match the outcome to the message it sent
+        match (result, nonce) {
+            (Ok(()), Some(nonce)) => {
+                let message = ServerMessage::Ack { nonce };
+                if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+                    println!("Failed to send ack: {}", e);
+                }
+            }
+            (Ok(()), None) => {}
+            (Err(error), nonce) => self.send_error(socket_id, nonce, error).await,
+        }
+
+        if failed_auth && self.lockout.exhausted(socket_id).await {
+            println!(
+                "Disconnecting socket {} after too many failed authentications",
+                socket_id
+            );
+            self.wspool
+                .close_socket(
+                    socket_id,
+                    Self::POLICY_VIOLATION,
+                    "Too many failed attempts",
+                )
+                .await;
+        }
+    }
+
+    async fn check_lockout(
+        &self,
+        socket_id: SocketId,
+        user_id: Option<Uuid>,
+    ) -> Result<(), ServerErrors> {
+        match self.lockout.locked_for(socket_id, user_id).await {
+            Some(remaining) => Err(ServerErrors::RateLimited {
+                retry_after_ms: remaining.as_millis().try_into().unwrap_or(u32::MAX),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_message(
+        &'static self,
+        socket_id: SocketId,
+        user_id: Option<Uuid>,
+        message: ClientMessage,
+    ) -> Result<(), ServerErrors> {
+        match message {
+            ClientMessage::DirectMessage { to, message } => {
+                // If the user is not logged in, we can't do anything
+                let from = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let to = parse_uuid(&to)?;
+
+                self.send_message(from, to, message).await?;
+            }
+            ClientMessage::SyncChat { with } => {
+                // If the user is not logged in, we can't do anything
+                let from = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let with = parse_uuid(&with)?;
+
+                let messages = self.history.get_messages(&from, &with).await;
+                let message = ServerMessage::BulkMessages {
+                    participants: [from.to_string(), with.to_string()],
+                    messages,
+                };
+
+                if let Err(e) = self.wspool.send_to_user(from, message).await {
+                    println!("Failed to send chat history: {}", e);
+                }
+            }
+            ClientMessage::FetchHistory {
+                with,
+                before,
+                limit,
+            } => {
+                let from = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let with = parse_uuid(&with)?;
+                let before = before.as_deref().map(parse_uuid).transpose()?;
+
+                let page = self
+                    .history
+                    .get_page(&from, &with, before, limit as usize)
+                    .await
+                    .map_err(|e| {
+                        println!("Failed to load history page: {}", e);
+                        ServerErrors::Internal
+                    })?;
+
+                let message = ServerMessage::HistoryPage {
+                    participants: [from.to_string(), with.to_string()],
+                    messages: page.messages,
+                    cursor: page.cursor,
+                    has_more: page.has_more,
+                };
+
+                if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+                    println!("Failed to send history page: {}", e);
+                }
+            }
+            ClientMessage::Ping => {
+                let message = ServerMessage::Pong;
+                if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+                    println!("Failed to send pong: {}", e);
+                }
+            }
+
+            ClientMessage::Authenticate { id, secret } => {
+                if user_id.is_some() {
+                    return Err(ServerErrors::AlreadyAuthenticated);
+                }
+
+                if !self.auth.legacy_secret() {
+                    return Err(ServerErrors::Unauthorized);
+                }
+
+                let id = parse_uuid(&id)?;
+                self.check_lockout(socket_id, Some(id)).await?;
+
+                let valid = match Uuid::parse_str(&secret) {
+                    Ok(secret) => self.verify_secret(&id, &secret).await,
+                    Err(_) => false,
+                };
+
+                if !valid {
+                    println!("Failed authentication on socket {}", socket_id);
+                    self.lockout.fail(socket_id, Some(id)).await;
+                    return Err(ServerErrors::InvalidSecret);
+                }
+
+                self.lockout.succeed(socket_id, id).await;
+                self.authenticate(socket_id, id, None).await?;
+            }
+
+            ClientMessage::AuthenticateToken { token } => {
+                if user_id.is_some() {
+                    return Err(ServerErrors::AlreadyAuthenticated);
+                }
+
+                self.check_lockout(socket_id, None).await?;
+
+                // `Unauthorized` only means tokens are off, the client falls back to the secret
+                let token = match self.auth.verify_token(&token) {
+                    Ok(token) => token,
+                    Err(ServerErrors::Unauthorized) => return Err(ServerErrors::Unauthorized),
+                    Err(e) => {
+                        self.lockout.fail(socket_id, None).await;
+                        return Err(e);
                     }
+                };
+
+                self.lockout.succeed(socket_id, token.user_id).await;
+                self.authenticate(socket_id, token.user_id, Some(token.issued_at))
+                    .await?;
+            }
+
+            ClientMessage::UserMeta { with } => {
+                user_id.ok_or(ServerErrors::Unauthorized)?;
+                let with = parse_uuid(&with)?;
 
-                    ClientMessage::UserMeta { with } => {
-                        let Some(_) = user_id else {
-                            self.send_error(socket_id, ServerErrors::Unauthorized).await;
-                            continue;
-                        };
+                let user = self.get_user_metadata(&with).await;
 
-                        let Ok(with) = Uuid::parse_str(&with) else {
-                            self.send_error(socket_id, ServerErrors::InvalidUuid).await;
-                            continue;
-                        };
+                let message = ServerMessage::UserMeta { user };
+                if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+                    println!("Failed to send user metadata: {}", e);
+                }
+            }
 
-                        let user = self.get_user_metadata(&with).await;
+            ClientMessage::SyncChatUsers => {
+                let user_id = user_id.ok_or(ServerErrors::Unauthorized)?;
 
-                        let message = ServerMessage::UserMeta { user };
-                        if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
-                            println!("Failed to send user metadata: {}", e);
-                        }
+                let blocked = self.blocks.blocked_by(&user_id).await.map_err(|e| {
+                    println!("Failed to load blocks: {}", e);
+                    ServerErrors::Internal
+                })?;
+
+                let mut users = Vec::new();
+                let mut unread = HashMap::new();
+                for chat in self.history.get_open_chats(&user_id).await {
+                    if blocked.contains(&chat.with) {
+                        continue;
                     }
 
-                    ClientMessage::SyncChatUsers => {
-                        let Some(user_id) = user_id else {
-                            self.send_error(socket_id, ServerErrors::Unauthorized).await;
-                            continue;
-                        };
-
-                        let open_chats = self
-                            .history
-                            .get_open_chats(&user_id.to_string())
-                            .await
-                            .read()
-                            .await
-                            .iter()
-                            .cloned()
-                            .collect();
-
-                        let message = ServerMessage::BulkUsers { users: open_chats };
-                        if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
-                            println!("Failed to send open chats: {}", e);
-                        }
+                    unread.insert(chat.with.to_string(), chat.unread);
+                    users.push(self.get_user_metadata(&chat.with).await);
+                }
+
+                let message = ServerMessage::BulkUsers { users, unread };
+                if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+                    println!("Failed to send open chats: {}", e);
+                }
+
+                self.send_blocked_users(socket_id, &user_id).await?;
+            }
+
+            ClientMessage::Block { user } => {
+                let blocker = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let blocked = parse_uuid(&user)?;
+
+                if blocker == blocked {
+                    return Err(ServerErrors::InvalidUser);
+                }
+
+                self.find_user(&blocked)
+                    .await
+                    .ok_or(ServerErrors::InvalidUser)?;
+
+                self.blocks.block(&blocker, &blocked).await.map_err(|e| {
+                    println!("Failed to block user: {}", e);
+                    ServerErrors::Internal
+                })?;
+
+                // neither is shown as typing to the other anymore
+                for (from, to) in [(blocker, blocked), (blocked, blocker)] {
+                    if self.typing.stop(from, to) {
+                        self.send_typing(from, to, TypingState::Stopped).await;
                     }
+                }
+
+                self.send_blocked_users(socket_id, &blocker).await?;
+            }
+
+            ClientMessage::ReportMessage { message_id, reason } => {
+                let reporter = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let message_id = parse_uuid(&message_id)?;
+
+                self.report_message(reporter, message_id, reason).await?;
+            }
+
+            ClientMessage::Admin { command } => {
+                let admin = user_id.ok_or(ServerErrors::Unauthorized)?;
+                self.admin(socket_id, admin, command).await?;
+            }
 
-                    ClientMessage::SetTopic { to, topic } => {
-                        let Some(user_id) = user_id else {
-                            self.send_error(socket_id, ServerErrors::Unauthorized).await;
-                            continue;
-                        };
+            ClientMessage::Unblock { user } => {
+                let blocker = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let blocked = parse_uuid(&user)?;
+
+                self.blocks.unblock(&blocker, &blocked).await.map_err(|e| {
+                    println!("Failed to unblock user: {}", e);
+                    ServerErrors::Internal
+                })?;
+
+                self.send_blocked_users(socket_id, &blocker).await?;
+            }
 
-                        let Ok(to) = Uuid::parse_str(&to) else {
-                            self.send_error(socket_id, ServerErrors::InvalidUuid).await;
-                            continue;
-                        };
+            ClientMessage::MarkRead { with, up_to } => {
+                let reader = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let with = parse_uuid(&with)?;
+                let up_to_id = parse_uuid(&up_to)?;
 
-                        self.set_topic(user_id, to, topic).await;
+                let marked = self
+                    .history
+                    .mark_read(&reader, &with, &up_to_id)
+                    .await
+                    .map_err(|e| {
+                        println!("Failed to mark chat as read: {}", e);
+                        ServerErrors::Internal
+                    })?;
+
+                if !marked {
+                    return Err(ServerErrors::InvalidMessage);
+                }
+
+                let message = ServerMessage::ReadReceipt {
+                    participants: [reader.to_string(), with.to_string()],
+                    reader: reader.to_string(),
+                    up_to,
+                };
+
+                if let Err(e) = self.wspool.send_to_users(&[reader, with], message).await {
+                    println!("Failed to send read receipt: {}", e);
+                }
+            }
+
+            ClientMessage::Typing { to, state } => {
+                let from = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let to = parse_uuid(&to)?;
+
+                // dropped without telling, the sender shouldn't learn about the block this way
+                if self.blocks.between(&from, &to).await {
+                    return Ok(());
+                }
+
+                self.typing(socket_id, from, to, state).await;
+            }
+
+            ClientMessage::QueryPresence { users } => {
+                let user_id = user_id.ok_or(ServerErrors::Unauthorized)?;
+
+                if users.len() > Self::MAX_PRESENCE_QUERY {
+                    return Err(ServerErrors::InvalidMessage);
+                }
+
+                let users = users
+                    .iter()
+                    .map(|user| parse_uuid(user))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                for user in users {
+                    if self.blocks.between(&user_id, &user).await {
+                        continue;
                     }
 
-                    ClientMessage::Disconnect => {
-                        self.wspool.remove_socket(socket_id).await;
+                    let message = self.presence(user).await;
+                    if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+                        println!("Failed to send presence: {}", e);
                     }
                 }
             }
-        });
+
+            ClientMessage::SetTopic { to, topic } => {
+                let user_id = user_id.ok_or(ServerErrors::Unauthorized)?;
+                let to = parse_uuid(&to)?;
+
+                self.set_topic(user_id, to, topic).await?;
+            }
+
+            ClientMessage::Disconnect => {
+                self.wspool.remove_socket(socket_id).await;
+            }
+        }
+
+        Ok(())
     }
 
-    async fn send_error(&self, socket_id: SocketId, error: ServerErrors) {
-        let message = ServerMessage::Error(error);
+    async fn authenticate(
+        &'static self,
+        socket_id: SocketId,
+        user_id: Uuid,
+        issued_at: Option<i64>,
+    ) -> Result<(), ServerErrors> {
+        self.check_session(&user_id, issued_at).await?;
+        self.wspool.authenticate(user_id, socket_id).await;
+
+        let message = ServerMessage::Authenticated;
+        println!("Authenticated: {}", user_id);
+        if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
+            println!("Failed to send authenticated: {}", e);
+        }
+
+        Ok(())
+    }
+
+    // refuses banned users, and tokens issued (`iat`) before the user's
+    // sessions were last revoked
+    pub async fn check_session(
+        &self,
+        user_id: &Uuid,
+        issued_at: Option<i64>,
+    ) -> Result<(), ServerErrors> {
+        let row =
+            sqlx::query("SELECT banned, sessions_revoked_at FROM user_moderation WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(&self.dbpool)
+                .await
+                .map_err(|e| {
+                    println!("Failed to check the user's session: {}", e);
+                    ServerErrors::Internal
+                })?;
+
+        let Some(row) = row else {
+            return Ok(());
+        };
+
+        let status: Result<(bool, Option<DateTime<Utc>>), sqlx::Error> =
+            try { (row.try_get("banned")?, row.try_get("sessions_revoked_at")?) };
+        let (banned, revoked_at) = status.map_err(|e| {
+            println!("Failed to check the user's session: {}", e);
+            ServerErrors::Internal
+        })?;
+
+        if banned {
+            return Err(ServerErrors::Banned);
+        }
+
+        match (issued_at, revoked_at) {
+            (Some(issued_at), Some(revoked_at)) if issued_at < revoked_at.timestamp() => {
+                Err(ServerErrors::InvalidToken)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // ends every session of the user, they have to authenticate again
+    pub async fn revoke_session(&'static self, user_id: Uuid) {
+        println!("Revoking session of {}", user_id);
+        self.metadata.invalidate(&user_id).await;
+
+        if let Err(e) = self
+            .wspool
+            .send_to_user(user_id, ServerMessage::SessionRevoked)
+            .await
+        {
+            println!("Failed to send session revoked: {}", e);
+        }
+
+        self.wspool
+            .close_user(user_id, Self::SESSION_REVOKED, "Session revoked")
+            .await;
+    }
+
+    // the database notifies us when a secret is regenerated or a user is banned
+    async fn listen_revocations(&'static self) {
+        let mut listener = loop {
+            let listener: Result<PgListener, sqlx::Error> = try {
+                let mut listener = PgListener::connect_with(&self.dbpool).await?;
+                listener.listen("session_revoked").await?;
+                listener
+            };
+
+            match listener {
+                Ok(listener) => break listener,
+                Err(e) => {
+                    println!("Failed to listen for revoked sessions: {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        };
+
+        loop {
+            // reconnects by itself if the connection is lost
+            match listener.recv().await {
+                Ok(notification) => match Uuid::parse_str(notification.payload()) {
+                    Ok(user_id) => self.revoke_session(user_id).await,
+                    Err(_) => println!("Invalid revoked session: {}", notification.payload()),
+                },
+                Err(e) => {
+                    println!("Failed to receive revoked sessions: {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+
+    async fn send_error(&self, socket_id: SocketId, nonce: Option<String>, error: ServerErrors) {
+        let message = ServerMessage::Error { nonce, error };
         if let Err(e) = self.wspool.send_to_socket(socket_id, message).await {
             println!("Failed to send error: {}", e);
         }
     }
 
     async fn fetch_user_metadata(&self, user_id: &Uuid) -> Option<ChatUser> {
-        let row = sqlx::query("SELECT * FROM verify WHERE id = $1")
-            .bind(user_id)
-            .fetch_one(&self.dbpool)
-            .await
-            .ok()?;
+        let row = sqlx::query(
+            "SELECT verify.email, user_info.role::text AS role FROM verify \
+             LEFT JOIN user_info ON user_info.id = verify.id WHERE verify.id = $1",
+        )
+        .bind(user_id)
+        .fetch_one(&self.dbpool)
+        .await
+        .ok()?;
+
+        let role = match row.try_get::<Option<String>, _>("role").ok()?.as_deref() {
+            Some("admin") => Role::Admin,
+            _ => Role::User,
+        };
 
         let user = ChatUser {
             id: user_id.to_string(),
             email: row.try_get("email").ok()?,
+            role,
         };
 
         Some(user)
     }
 
-    async fn get_user_metadata(&self, user_id: &Uuid) -> ChatUser {
+    // only users that exist are cached, so a new account is found as soon as it is created
+    async fn find_user(&self, user_id: &Uuid) -> Option<ChatUser> {
         self.metadata
-            .get_with_by_ref(user_id, async {
-                self.fetch_user_metadata(user_id).await.unwrap_or(ChatUser {
-                    id: user_id.to_string(),
-                    email: "Unknown".to_string(),
-                })
-            })
+            .optionally_get_with_by_ref(user_id, self.fetch_user_metadata(user_id))
             .await
     }
 
+    async fn get_user_metadata(&self, user_id: &Uuid) -> ChatUser {
+        self.find_user(user_id).await.unwrap_or(ChatUser {
+            id: user_id.to_string(),
+            email: "Unknown".to_string(),
+            role: Role::User,
+        })
+    }
+
     async fn verify_secret(&self, uuid: &Uuid, secret: &Uuid) -> bool {
         let row = sqlx::query("SELECT secret FROM verify WHERE id = $1")
             .bind(uuid)
-            .fetch_one(&self.dbpool)
+            .fetch_optional(&self.dbpool)
             .await;
 
-        let Ok(row) = row else {
-            println!("Failed to fetch secret from database!?");
-            return false;
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                println!("Failed to fetch secret from database: {}", e);
+                return false;
+            }
         };
 
-        let Ok(found_secret) = row.try_get::<Uuid, _>("secret") else {
-            return false;
-        };
+        // unknown users are compared against a secret nobody has, so they take as long
+        let found_secret = row
+            .and_then(|row| row.try_get::<Uuid, _>("secret").ok())
+            .unwrap_or(Uuid::nil());
 
-        found_secret == *secret
+        auth::secrets_match(&found_secret, secret) && !found_secret.is_nil()
     }
 }
+
+fn parse_uuid(id: &str) -> Result<Uuid, ServerErrors> {
+    Uuid::parse_str(id).map_err(|_| ServerErrors::InvalidUuid)
+}
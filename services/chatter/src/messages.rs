@@ -1,7 +1,9 @@
-use std::io::Write;
+use std::{collections::HashMap, io::Write};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use uuid::Uuid;
 
 #[derive(Type, Clone, Debug, Serialize)]
 #[serde(tag = "type")]
@@ -9,14 +11,21 @@ pub enum ServerMessage {
     Pong,
     Authenticated,
 
-    Error(ServerErrors),
+    Ack {
+        nonce: String,
+    }, // The message with this nonce was handled
+    Error {
+        nonce: Option<String>,
+        error: ServerErrors,
+    }, // Handling a message failed, `nonce` is set if the message had one
 
     UserMeta {
         user: ChatUser,
     }, // Send the user's metadata to the client
     BulkUsers {
         users: Vec<ChatUser>,
-    }, // Send a bulk of users to the client
+        unread: HashMap<String, u32>,
+    }, // Send a bulk of users to the client, with the unread count of each chat
 
     BulkMessages {
         participants: [String; 2],
@@ -26,6 +35,43 @@ pub enum ServerMessage {
         participants: [String; 2],
         message: ChatMessage,
     }, // Send a single message to the client
+    HistoryPage {
+        participants: [String; 2],
+        messages: Vec<ChatMessage>,
+        cursor: Option<MessageId>,
+        has_more: bool,
+    }, // Send a page of older messages, `cursor` is the `before` of the next page
+    Delivered {
+        participants: [String; 2],
+        message_id: MessageId,
+    }, // The message reached the recipient
+    ReadReceipt {
+        participants: [String; 2],
+        reader: String,
+        up_to: MessageId,
+    }, // `reader` has read every message up to and including `up_to`
+    Typing {
+        from: String,
+        state: TypingState,
+    }, // The other participant started or stopped typing
+    Presence {
+        user: String,
+        online: bool,
+        last_seen: Option<DateTime<Utc>>,
+    }, // A user came online or went offline
+    BlockedUsers {
+        users: Vec<String>,
+    }, // The users we blocked
+    Reports {
+        reports: Vec<Report>,
+    }, // Open reports, only sent to admins
+    ModerationNotice {
+        action: ReportAction,
+    }, // An admin acted on a report against us
+    Announcement {
+        message: ChatMessage,
+    }, // A message from the admins to everyone online
+    SessionRevoked, // The user was banned or their credentials changed, the socket is about to close
 }
 
 #[derive(Type, Clone, Debug, Serialize)]
@@ -35,9 +81,14 @@ pub enum ServerErrors {
     AlreadyAuthenticated,
     InvalidUuid,
     InvalidSecret,
+    InvalidToken,
     InvalidMessage,
     InvalidUser,
-    RateLimited,
+    Banned,
+    Blocked,
+    Forbidden,
+    Muted { until: DateTime<Utc> },
+    RateLimited { retry_after_ms: u32 },
 }
 
 #[derive(Type, Clone, Debug, Serialize, Deserialize)]
@@ -45,28 +96,252 @@ pub enum ServerErrors {
 pub enum ClientMessage {
     Ping,
 
-    Disconnect,                                    // Disconnect from the server
-    Authenticate { id: String, secret: String },   // Authenticate the user
-    SyncChat { with: String },                     // Sync chat with a user (ask for chat history)
-    DirectMessage { to: String, message: String }, // Send a message to a user
-    SetTopic { to: String, topic: String },        // Set the topic of the chat
+    Disconnect, // Disconnect from the server
+    Authenticate {
+        id: String,
+        secret: String,
+    }, // Authenticate the user
+    AuthenticateToken {
+        token: String,
+    }, // Authenticate with a Supabase access token
+    SyncChat {
+        with: String,
+    }, // Sync chat with a user (ask for chat history)
+    DirectMessage {
+        to: String,
+        message: String,
+    }, // Send a message to a user
+    SetTopic {
+        to: String,
+        topic: String,
+    }, // Set the topic of the chat
+    FetchHistory {
+        with: String,
+        before: Option<MessageId>,
+        limit: u32,
+    }, // Fetch a page of messages older than `before` (latest page if none)
+    MarkRead {
+        with: String,
+        up_to: MessageId,
+    }, // Mark the chat as read up to and including a message
+    Typing {
+        to: String,
+        state: TypingState,
+    }, // Tell a user we started or stopped typing
+    QueryPresence {
+        users: Vec<String>,
+    }, // Ask whether users are online (answered with a `Presence` per user)
+
+    UserMeta {
+        with: String,
+    }, // Sync chat user (ask for user metadata)
+    SyncChatUsers, // Sync chat users (ask for all open chat users)
+    Block {
+        user: String,
+    }, // Stop hearing from a user (answered with `BlockedUsers`)
+    Unblock {
+        user: String,
+    }, // Undo a block (answered with `BlockedUsers`)
+    ReportMessage {
+        message_id: MessageId,
+        reason: String,
+    }, // Report a message we received to the admins
+    Admin {
+        command: AdminCommand,
+    }, // Only accepted from admins
+}
+
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AdminCommand {
+    ListReports, // answered with `Reports`
+    ResolveReport {
+        report_id: String,
+        action: ReportAction,
+    },
+    ReadChat {
+        between: [String; 2],
+        before: Option<MessageId>,
+        limit: u32,
+    }, // read any conversation, answered with `HistoryPage`
+    Broadcast {
+        message: String,
+        users: Option<Vec<String>>,
+    }, // posted into the conversations of the users (everyone if not set)
+    MuteUser {
+        user: String,
+        minutes: u32,
+    },
+    DisconnectUser {
+        user: String,
+    }, // closes every socket of the user
+}
 
-    UserMeta { with: String }, // Sync chat user (ask for user metadata)
-    SyncChatUsers,             // Sync chat users (ask for all open chat users)
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ReportAction {
+    Dismiss,
+    Warn { message: String },
+    Mute { minutes: u32 },
+    Ban,
 }
 
 #[derive(Type, Clone, Debug, Serialize)]
+pub struct Report {
+    pub id: String,
+    pub reporter: ChatUser,
+    pub reported: ChatUser,
+    pub message_id: MessageId,
+    pub reason: String,
+    // the conversation around the reported message when it was reported
+    pub context: Vec<ChatMessage>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ClientMessage {
+    // the `type` tag of every variant
+    pub const KINDS: [&'static str; 17] = [
+        "Ping",
+        "Disconnect",
+        "Authenticate",
+        "AuthenticateToken",
+        "SyncChat",
+        "DirectMessage",
+        "SetTopic",
+        "FetchHistory",
+        "MarkRead",
+        "Typing",
+        "QueryPresence",
+        "UserMeta",
+        "SyncChatUsers",
+        "Block",
+        "Unblock",
+        "ReportMessage",
+        "Admin",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Ping => "Ping",
+            Self::Disconnect => "Disconnect",
+            Self::Authenticate { .. } => "Authenticate",
+            Self::AuthenticateToken { .. } => "AuthenticateToken",
+            Self::SyncChat { .. } => "SyncChat",
+            Self::DirectMessage { .. } => "DirectMessage",
+            Self::SetTopic { .. } => "SetTopic",
+            Self::FetchHistory { .. } => "FetchHistory",
+            Self::MarkRead { .. } => "MarkRead",
+            Self::Typing { .. } => "Typing",
+            Self::QueryPresence { .. } => "QueryPresence",
+            Self::UserMeta { .. } => "UserMeta",
+            Self::SyncChatUsers => "SyncChatUsers",
+            Self::Block { .. } => "Block",
+            Self::Unblock { .. } => "Unblock",
+            Self::ReportMessage { .. } => "ReportMessage",
+            Self::Admin { .. } => "Admin",
+        }
+    }
+}
+
+pub type MessageId = String;
+
+#[derive(Type, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TypingState {
+    Started,
+    Stopped,
+}
+
+// What the client actually sends, the optional nonce is echoed back
+// in the `Ack` or `Error` for the message
+#[derive(Type, Clone, Debug, Deserialize)]
+pub struct ClientRequest {
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ChatMessage {
-    User { from: String, message: String }, // A message from a user
-    Topic { topic: String },                // What the topic of the chat is
-    Server { message: String },             // A message from the server
+    // A message from a user
+    User {
+        id: MessageId,
+        sent_at: DateTime<Utc>,
+        from: String,
+        message: String,
+    },
+    // What the topic of the chat is
+    Topic {
+        id: MessageId,
+        sent_at: DateTime<Utc>,
+        topic: String,
+    },
+    // A message from the server
+    Server {
+        id: MessageId,
+        sent_at: DateTime<Utc>,
+        message: String,
+    },
+}
+
+impl ChatMessage {
+    // UUIDv7 ids sort by the time they were created
+    fn stamp() -> (MessageId, DateTime<Utc>) {
+        (Uuid::now_v7().to_string(), Utc::now())
+    }
+
+    pub fn user(from: String, message: String) -> Self {
+        let (id, sent_at) = Self::stamp();
+        Self::User {
+            id,
+            sent_at,
+            from,
+            message,
+        }
+    }
+
+    pub fn topic(topic: String) -> Self {
+        let (id, sent_at) = Self::stamp();
+        Self::Topic { id, sent_at, topic }
+    }
+
+    pub fn server(message: String) -> Self {
+        let (id, sent_at) = Self::stamp();
+        Self::Server {
+            id,
+            sent_at,
+            message,
+        }
+    }
+
+    pub fn id(&self) -> &MessageId {
+        match self {
+            Self::User { id, .. } | Self::Topic { id, .. } | Self::Server { id, .. } => id,
+        }
+    }
+
+    pub fn sent_at(&self) -> DateTime<Utc> {
+        match self {
+            Self::User { sent_at, .. }
+            | Self::Topic { sent_at, .. }
+            | Self::Server { sent_at, .. } => *sent_at,
+        }
+    }
 }
 
 #[derive(Type, Clone, Debug, Serialize, PartialEq, Eq, Hash)]
 pub struct ChatUser {
     pub id: String,
     pub email: String,
+    pub role: Role,
+}
+
+// `user_info.role`
+#[derive(Type, Clone, Copy, Debug, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    User,
 }
 
 macro_rules! specta_buffer {
@@ -88,7 +363,7 @@ pub fn export_types() {
     }
 
     let definitions = specta_buffer! {
-        ChatUser | ServerMessage | ServerErrors | ClientMessage | ChatMessage,
+        ChatUser | Role | ServerMessage | ServerErrors | ClientMessage | ClientRequest | ChatMessage | TypingState | AdminCommand | ReportAction | Report,
         r#"
 export type ClientMessageTypes = ClientMessage["type"];
 export type ServerMessageTypes = ServerMessage["type"];
@@ -0,0 +1,235 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use moka::future::Cache;
+use sqlx::{types::Json, PgExecutor, Row};
+use uuid::Uuid;
+
+use crate::{
+    filter::FilterHit,
+    messages::{ChatMessage, ReportAction},
+};
+
+pub struct StoredReport {
+    pub id: Uuid,
+    pub reporter: Uuid,
+    pub reported: Uuid,
+    pub message_id: Uuid,
+    pub reason: String,
+    pub context: Vec<ChatMessage>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Reports and the sanctions admins hand out for them
+pub struct ModerationManager {
+    dbpool: sqlx::PgPool,
+    // checked for every message sent, so it is only read from the database now and then
+    muted: Cache<Uuid, Option<DateTime<Utc>>>,
+}
+
+impl ModerationManager {
+    const MAX_LISTED_REPORTS: i64 = 100;
+
+    pub fn new(dbpool: sqlx::PgPool) -> Self {
+        Self {
+            dbpool,
+            muted: Cache::builder()
+                .time_to_live(Duration::from_secs(30))
+                .build(),
+        }
+    }
+
+    // read from the database every time, a demoted admin loses access right away
+    pub async fn is_admin(&self, user: &Uuid) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query("SELECT role::text AS role FROM user_info WHERE id = $1")
+            .bind(user)
+            .fetch_optional(&self.dbpool)
+            .await?;
+
+        Ok(match row {
+            Some(row) => row.try_get::<String, _>("role")? == "admin",
+            None => false,
+        })
+    }
+
+    // when the user's mute ends, if they are muted
+    pub async fn muted_until(
+        &self,
+        user: &Uuid,
+    ) -> Result<Option<DateTime<Utc>>, Arc<sqlx::Error>> {
+        let until = self
+            .muted
+            .try_get_with_by_ref(user, async {
+                let row = sqlx::query("SELECT muted_until FROM user_moderation WHERE id = $1")
+                    .bind(user)
+                    .fetch_optional(&self.dbpool)
+                    .await?;
+
+                match row {
+                    Some(row) => row.try_get::<Option<DateTime<Utc>>, _>("muted_until"),
+                    None => Ok(None),
+                }
+            })
+            .await?;
+
+        Ok(until.filter(|until| *until > Utc::now()))
+    }
+
+    pub async fn mute(&self, user: &Uuid, until: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        Self::set_muted_until(&self.dbpool, user, until).await?;
+
+        self.muted.insert(*user, Some(until)).await;
+        Ok(())
+    }
+
+    async fn set_muted_until(
+        executor: impl PgExecutor<'_>,
+        user: &Uuid,
+        until: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO user_moderation (id, muted_until) VALUES ($1, $2) \
+             ON CONFLICT (id) DO UPDATE SET muted_until = $2",
+        )
+        .bind(user)
+        .bind(until)
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    // the database revokes the user's sessions once they are banned
+    async fn ban(executor: impl PgExecutor<'_>, user: &Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO user_moderation (id, banned) VALUES ($1, true) \
+             ON CONFLICT (id) DO UPDATE SET banned = true",
+        )
+        .bind(user)
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    // returns false if the reporter already reported the message
+    pub async fn report(
+        &self,
+        reporter: &Uuid,
+        reported: &Uuid,
+        message_id: &Uuid,
+        reason: &str,
+        context: &[ChatMessage],
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "INSERT INTO reports (reporter, reported, message_id, reason, context) \
+             VALUES ($1, $2, $3, $4, $5) ON CONFLICT (reporter, message_id) DO NOTHING",
+        )
+        .bind(reporter)
+        .bind(reported)
+        .bind(message_id)
+        .bind(reason)
+        .bind(Json(context))
+        .execute(&self.dbpool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // keeps the original message for every rule it tripped
+    pub async fn log_filter_hits(
+        &self,
+        sender: &Uuid,
+        recipient: &Uuid,
+        body: &str,
+        hits: &[FilterHit],
+    ) -> Result<(), sqlx::Error> {
+        for hit in hits {
+            sqlx::query(
+                "INSERT INTO filter_hits (sender, recipient, rule, action, body) \
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(sender)
+            .bind(recipient)
+            .bind(&hit.rule)
+            .bind(hit.action.as_str())
+            .bind(body)
+            .execute(&self.dbpool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    // unresolved reports, oldest first
+    pub async fn open_reports(&self) -> Result<Vec<StoredReport>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT id, reporter, reported, message_id, reason, context, created_at \
+             FROM reports WHERE resolved_at IS NULL ORDER BY created_at LIMIT $1",
+        )
+        .bind(Self::MAX_LISTED_REPORTS)
+        .fetch_all(&self.dbpool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(StoredReport {
+                    id: row.try_get("id")?,
+                    reporter: row.try_get("reporter")?,
+                    reported: row.try_get("reported")?,
+                    message_id: row.try_get("message_id")?,
+                    reason: row.try_get("reason")?,
+                    context: row.try_get::<Json<Vec<ChatMessage>>, _>("context")?.0,
+                    created_at: row.try_get("created_at")?,
+                })
+            })
+            .collect()
+    }
+
+    // marks the report resolved and hands out the sanction in one transaction,
+    // returns the reported user or `None` if there is no such open report
+    pub async fn resolve(
+        &self,
+        report_id: &Uuid,
+        admin: &Uuid,
+        action: &ReportAction,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let mut tx = self.dbpool.begin().await?;
+
+        let row = sqlx::query(
+            "UPDATE reports SET resolved_by = $2, resolved_at = CURRENT_TIMESTAMP, resolution = $3 \
+             WHERE id = $1 AND resolved_at IS NULL RETURNING reported",
+        )
+        .bind(report_id)
+        .bind(admin)
+        .bind(Json(action))
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let reported: Uuid = row.try_get("reported")?;
+
+        let muted_until = match action {
+            ReportAction::Mute { minutes } => {
+                let until = Utc::now() + chrono::Duration::minutes((*minutes).into());
+                Self::set_muted_until(&mut *tx, &reported, until).await?;
+                Some(until)
+            }
+            ReportAction::Ban => {
+                Self::ban(&mut *tx, &reported).await?;
+                None
+            }
+            ReportAction::Dismiss | ReportAction::Warn { .. } => None,
+        };
+
+        tx.commit().await?;
+
+        if let Some(until) = muted_until {
+            self.muted.insert(reported, Some(until)).await;
+        }
+
+        Ok(Some(reported))
+    }
+}
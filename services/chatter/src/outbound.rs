@@ -0,0 +1,229 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::extract::ws::Message;
+use futures::{Sink, SinkExt};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+// What to do with a message for a socket whose queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // make room by dropping the oldest queued message
+    DropOldest,
+    // the consumer is too slow to keep up, close the socket
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    Closed,
+    Full,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "socket is closed"),
+            Self::Full => write!(f, "socket queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+// Counters shared by the queues of every socket
+#[derive(Default)]
+pub struct QueueMetrics {
+    queued: AtomicUsize,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueueStats {
+    pub queued: usize,
+    pub dropped: u64,
+    pub slow_consumers_disconnected: u64,
+}
+
+impl QueueMetrics {
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            slow_consumers_disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    // no more messages are accepted, the writer stops once the queue is drained
+    closed: bool,
+}
+
+enum Next {
+    Message(Message),
+    // closed and drained
+    Closed,
+    Empty,
+}
+
+// A bounded queue of messages drained into the socket's sink by its own writer task,
+// so a slow socket never holds up whoever is sending to it
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl OutboundQueue {
+    pub fn new<S>(
+        sink: S,
+        capacity: usize,
+        policy: OverflowPolicy,
+        metrics: Arc<QueueMetrics>,
+    ) -> Arc<Self>
+    where
+        S: Sink<Message, Error = axum::Error> + Send + Unpin + 'static,
+    {
+        let queue = Arc::new(Self {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            notify: Notify::new(),
+            capacity,
+            policy,
+            metrics,
+        });
+
+        tokio::spawn(queue.clone().write(sink));
+        queue
+    }
+
+    pub fn push(&self, message: Message) -> Result<(), QueueError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(QueueError::Closed);
+        }
+
+        if state.messages.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.messages.pop_front();
+                    self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::Disconnect => {
+                    self.close_locked(&mut state);
+                    self.metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+                    return Err(QueueError::Full);
+                }
+            }
+        }
+
+        state.messages.push_back(message);
+        self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        drop(state);
+
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    // queues a final message (e.g. a close frame) and stops accepting new ones
+    pub fn close_with(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        state.messages.push_back(message);
+        self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        state.closed = true;
+        drop(state);
+
+        self.notify.notify_one();
+    }
+
+    // stops accepting messages, the writer closes the sink once the rest is written
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        drop(state);
+
+        self.notify.notify_one();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    // drops whatever is still queued, the writer closes the sink
+    fn close_locked(&self, state: &mut QueueState) {
+        let dropped = state.messages.len();
+        state.messages.clear();
+        state.closed = true;
+        self.metrics.queued.fetch_sub(dropped, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    fn pop(&self) -> Next {
+        let mut state = self.state.lock().unwrap();
+        match state.messages.pop_front() {
+            Some(message) => {
+                self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                Next::Message(message)
+            }
+            None if state.closed => Next::Closed,
+            None => Next::Empty,
+        }
+    }
+
+    async fn write<S>(self: Arc<Self>, mut sink: S)
+    where
+        S: Sink<Message, Error = axum::Error> + Send + Unpin + 'static,
+    {
+        loop {
+            match self.pop() {
+                Next::Message(message) => {
+                    if let Err(e) = sink.send(message).await {
+                        println!("Error writing to socket: {}", e);
+                        self.close_locked(&mut self.state.lock().unwrap());
+                        break;
+                    }
+                }
+                Next::Closed => break,
+                Next::Empty => self.notify.notified().await,
+            }
+        }
+
+        let _ = sink.close().await;
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use moka::future::Cache;
+use uuid::Uuid;
+
+use crate::{config, ws::SocketId};
+
+// A bucket that holds up to `burst` tokens and refills `per_second` tokens every second
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Quota {
+    pub const fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+}
+
+// parses `<burst>:<per_second>`, e.g. `10:2`
+impl FromStr for Quota {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, per_second) = s.split_once(':').ok_or(())?;
+        let burst = burst.trim().parse().map_err(|_| ())?;
+        let per_second: f64 = per_second.trim().parse().map_err(|_| ())?;
+
+        if burst == 0 || per_second <= 0.0 {
+            return Err(());
+        }
+
+        Ok(Self { burst, per_second })
+    }
+}
+
+// The quotas of one kind of client message, every socket and every user gets their own bucket
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub socket: Quota,
+    pub user: Quota,
+}
+
+pub struct RateLimits {
+    default: Limit,
+    kinds: HashMap<&'static str, Limit>,
+    // violations within `window` after which the socket is disconnected
+    max_violations: u32,
+    window: Duration,
+}
+
+impl RateLimits {
+    const DEFAULT: Limit = Limit {
+        socket: Quota::new(20, 5.0),
+        user: Quota::new(40, 10.0),
+    };
+
+    // a client asks for the history of every open chat when it logs in
+    const HISTORY: Limit = Limit {
+        socket: Quota::new(100, 5.0),
+        user: Quota::new(200, 10.0),
+    };
+
+    const KINDS: [(&'static str, Limit); 8] = [
+        (
+            "Authenticate",
+            Limit {
+                socket: Quota::new(5, 0.2),
+                user: Quota::new(10, 0.5),
+            },
+        ),
+        (
+            "AuthenticateToken",
+            Limit {
+                socket: Quota::new(5, 0.2),
+                user: Quota::new(10, 0.5),
+            },
+        ),
+        (
+            "DirectMessage",
+            Limit {
+                socket: Quota::new(10, 2.0),
+                user: Quota::new(20, 4.0),
+            },
+        ),
+        ("FetchHistory", Self::HISTORY),
+        (
+            "ReportMessage",
+            Limit {
+                socket: Quota::new(3, 0.05),
+                user: Quota::new(5, 0.1),
+            },
+        ),
+        (
+            "SetTopic",
+            Limit {
+                socket: Quota::new(5, 0.5),
+                user: Quota::new(10, 1.0),
+            },
+        ),
+        ("SyncChat", Self::HISTORY),
+        (
+            "Typing",
+            Limit {
+                socket: Quota::new(10, 2.0),
+                user: Quota::new(20, 4.0),
+            },
+        ),
+    ];
+
+    // every kind can be overridden with RATE_LIMIT_<KIND> (per socket)
+    // and RATE_LIMIT_USER_<KIND> (per user), e.g. RATE_LIMIT_DIRECTMESSAGE=10:2
+    pub fn from_env(kinds: &[&'static str]) -> Self {
+        let defaults: HashMap<_, _> = Self::KINDS.into_iter().collect();
+
+        let kinds = kinds
+            .iter()
+            .map(|&kind| {
+                let default = defaults.get(kind).copied().unwrap_or(Self::DEFAULT);
+                let key = kind.to_uppercase();
+                let limit = Limit {
+                    socket: config::env_or(&format!("RATE_LIMIT_{key}"), default.socket),
+                    user: config::env_or(&format!("RATE_LIMIT_USER_{key}"), default.user),
+                };
+
+                (kind, limit)
+            })
+            .collect();
+
+        Self {
+            default: Self::DEFAULT,
+            kinds,
+            max_violations: config::env_or("RATE_LIMIT_MAX_VIOLATIONS", 20),
+            window: config::env_secs_or("RATE_LIMIT_WINDOW_SECS", 60),
+        }
+    }
+
+    fn limit(&self, kind: &str) -> Limit {
+        self.kinds.get(kind).copied().unwrap_or(self.default)
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(quota: Quota) -> Self {
+        Self::full_at(quota, Instant::now())
+    }
+
+    fn full_at(quota: Quota, now: Instant) -> Self {
+        Self {
+            tokens: quota.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn take(&mut self, quota: Quota) -> Result<(), Duration> {
+        self.take_at(quota, Instant::now())
+    }
+
+    // takes a token, or returns how long until one is available
+    fn take_at(&mut self, quota: Quota, now: Instant) -> Result<(), Duration> {
+        let refill = now.duration_since(self.updated).as_secs_f64() * quota.per_second;
+        self.tokens = (self.tokens + refill).min(quota.burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / quota.per_second,
+            ))
+        }
+    }
+}
+
+type Bucket = Arc<Mutex<TokenBucket>>;
+
+pub enum RateLimited {
+    // try again after the duration
+    Retry(Duration),
+    // the socket kept going over its limits and should be disconnected
+    Disconnect,
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    sockets: Cache<(SocketId, &'static str), Bucket>,
+    users: Cache<(Uuid, &'static str), Bucket>,
+    violations: Cache<SocketId, Arc<AtomicU32>>,
+}
+
+impl RateLimiter {
+    // a bucket idle this long is full again for any sane quota
+    const IDLE: Duration = Duration::from_secs(10 * 60);
+
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            violations: Cache::builder().time_to_live(limits.window).build(),
+            limits,
+            sockets: Cache::builder().time_to_idle(Self::IDLE).build(),
+            users: Cache::builder().time_to_idle(Self::IDLE).build(),
+        }
+    }
+
+    pub async fn check(
+        &self,
+        socket_id: SocketId,
+        user_id: Option<Uuid>,
+        kind: &'static str,
+    ) -> Result<(), RateLimited> {
+        let limit = self.limits.limit(kind);
+
+        let socket = self
+            .sockets
+            .get_with((socket_id, kind), async {
+                Arc::new(Mutex::new(TokenBucket::full(limit.socket)))
+            })
+            .await;
+
+        let mut result = socket.lock().unwrap().take(limit.socket);
+
+        if let (Ok(()), Some(user_id)) = (result, user_id) {
+            let user = self
+                .users
+                .get_with((user_id, kind), async {
+                    Arc::new(Mutex::new(TokenBucket::full(limit.user)))
+                })
+                .await;
+
+            result = user.lock().unwrap().take(limit.user);
+        }
+
+        let Err(retry_after) = result else {
+            return Ok(());
+        };
+
+        let violations = self
+            .violations
+            .get_with(socket_id, async { Arc::new(AtomicU32::new(0)) })
+            .await;
+
+        if violations.fetch_add(1, Ordering::Relaxed) + 1 >= self.limits.max_violations {
+            return Err(RateLimited::Disconnect);
+        }
+
+        Err(RateLimited::Retry(retry_after))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: Quota = Quota::new(2, 2.0);
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_says_when_to_retry() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full_at(QUOTA, now);
+
+        assert_eq!(bucket.take_at(QUOTA, now), Ok(()));
+        assert_eq!(bucket.take_at(QUOTA, now), Ok(()));
+        assert_eq!(bucket.take_at(QUOTA, now), Err(millis(500)));
+
+        // half a token came back, the other half is still missing
+        assert_eq!(bucket.take_at(QUOTA, now + millis(250)), Err(millis(250)));
+        assert_eq!(bucket.take_at(QUOTA, now + millis(500)), Ok(()));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full_at(QUOTA, now);
+        bucket.take_at(QUOTA, now).unwrap();
+        bucket.take_at(QUOTA, now).unwrap();
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.take_at(QUOTA, later), Ok(()));
+        assert_eq!(bucket.take_at(QUOTA, later), Ok(()));
+        assert!(bucket.take_at(QUOTA, later).is_err());
+    }
+
+    #[test]
+    fn quota_parses_burst_and_rate() {
+        let quota: Quota = "10:2.5".parse().unwrap();
+        assert_eq!((quota.burst, quota.per_second), (10, 2.5));
+
+        assert!("10".parse::<Quota>().is_err());
+        assert!("0:1".parse::<Quota>().is_err());
+        assert!("10:0".parse::<Quota>().is_err());
+    }
+
+    fn limiter(max_violations: u32) -> RateLimiter {
+        let limit = Limit {
+            socket: Quota::new(1, 0.001),
+            user: Quota::new(1, 0.001),
+        };
+
+        RateLimiter::new(RateLimits {
+            default: limit,
+            kinds: HashMap::new(),
+            max_violations,
+            window: Duration::from_secs(60),
+        })
+    }
+
+    #[tokio::test]
+    async fn repeat_offenders_are_disconnected() {
+        let limiter = limiter(3);
+        let socket_id = SocketId::next();
+
+        assert!(limiter.check(socket_id, None, "Ping").await.is_ok());
+        for _ in 0..2 {
+            let result = limiter.check(socket_id, None, "Ping").await;
+            assert!(matches!(result, Err(RateLimited::Retry(_))));
+        }
+
+        let result = limiter.check(socket_id, None, "Ping").await;
+        assert!(matches!(result, Err(RateLimited::Disconnect)));
+    }
+
+    #[tokio::test]
+    async fn users_share_a_bucket_across_sockets() {
+        let limiter = limiter(10);
+        let user_id = Uuid::new_v4();
+
+        let first = limiter.check(SocketId::next(), Some(user_id), "Ping").await;
+        assert!(first.is_ok());
+
+        let second = limiter.check(SocketId::next(), Some(user_id), "Ping").await;
+        assert!(matches!(second, Err(RateLimited::Retry(_))));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use moka::future::Cache;
+use uuid::Uuid;
+
+use crate::ws::SocketId;
+
+// Typing state is never stored, it only lives here until it is stopped or expires
+pub struct TypingTracker {
+    // last time `from` said they were typing to `to`
+    active: Mutex<HashMap<(Uuid, Uuid), Instant>>,
+    // sockets that recently had a typing event relayed
+    throttled: Cache<SocketId, ()>,
+}
+
+impl TypingTracker {
+    // how long someone is shown as typing without hearing from them again
+    pub const TIMEOUT: Duration = Duration::from_secs(5);
+    // minimum time between two relayed typing events of a socket
+    pub const THROTTLE: Duration = Duration::from_millis(500);
+
+    pub fn new() -> Self {
+        Self {
+            active: Mutex::new(HashMap::new()),
+            throttled: Cache::builder().time_to_live(Self::THROTTLE).build(),
+        }
+    }
+
+    // returns false if the socket already had a typing event relayed recently
+    pub async fn allow(&self, socket_id: SocketId) -> bool {
+        if self.throttled.contains_key(&socket_id) {
+            return false;
+        }
+
+        self.throttled.insert(socket_id, ()).await;
+        true
+    }
+
+    // extends the typing state, returns false if `from` is not typing to `to`
+    pub fn refresh(&self, from: Uuid, to: Uuid) -> bool {
+        let mut active = self.active.lock().unwrap();
+        match active.get_mut(&(from, to)) {
+            Some(last) => {
+                *last = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn start(&self, from: Uuid, to: Uuid) {
+        let mut active = self.active.lock().unwrap();
+        active.insert((from, to), Instant::now());
+    }
+
+    // returns true if `from` was typing to `to`
+    pub fn stop(&self, from: Uuid, to: Uuid) -> bool {
+        let mut active = self.active.lock().unwrap();
+        active.remove(&(from, to)).is_some()
+    }
+
+    // how long until the typing state expires, `None` if it was stopped,
+    // a zero duration means it just expired and has been removed
+    pub fn expires_in(&self, from: Uuid, to: Uuid) -> Option<Duration> {
+        let mut active = self.active.lock().unwrap();
+        let last = *active.get(&(from, to))?;
+
+        match Self::TIMEOUT.checked_sub(last.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => Some(remaining),
+            _ => {
+                active.remove(&(from, to));
+                Some(Duration::ZERO)
+            }
+        }
+    }
+}
+
+impl Default for TypingTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
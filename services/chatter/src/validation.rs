@@ -0,0 +1,50 @@
+use crate::{config, messages::ServerErrors};
+
+// Checks user supplied text before it is stored or relayed
+pub struct Validator {
+    max_message_chars: usize,
+    max_topic_chars: usize,
+    max_reason_chars: usize,
+}
+
+impl Validator {
+    pub fn from_env() -> Self {
+        Self {
+            max_message_chars: config::env_or("MAX_MESSAGE_CHARS", 2000),
+            max_topic_chars: config::env_or("MAX_TOPIC_CHARS", 64),
+            max_reason_chars: config::env_or("MAX_REPORT_REASON_CHARS", 500),
+        }
+    }
+
+    pub fn message(&self, message: &str) -> Result<String, ServerErrors> {
+        clean(message, self.max_message_chars, true)
+    }
+
+    pub fn topic(&self, topic: &str) -> Result<String, ServerErrors> {
+        clean(topic, self.max_topic_chars, false)
+    }
+
+    pub fn reason(&self, reason: &str) -> Result<String, ServerErrors> {
+        clean(reason, self.max_reason_chars, true)
+    }
+}
+
+// characters that reorder how text is displayed, used to disguise links and file names
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+// strips control characters, trims, and rejects empty or overlong text
+fn clean(text: &str, max_chars: usize, multiline: bool) -> Result<String, ServerErrors> {
+    let cleaned: String = text
+        .chars()
+        .filter(|&c| (multiline && c == '\n') || !(c.is_control() || is_bidi_control(c)))
+        .collect();
+
+    let trimmed = cleaned.trim();
+    if trimmed.is_empty() || trimmed.chars().count() > max_chars {
+        return Err(ServerErrors::InvalidMessage);
+    }
+
+    Ok(trimmed.to_string())
+}
@@ -1,18 +1,60 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use chrono::{DateTime, Utc};
 use futures::{
-    channel::mpsc::{UnboundedReceiver, UnboundedSender},
-    stream::SplitSink,
-    SinkExt, StreamExt,
+    channel::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender},
+    Sink, SinkExt, StreamExt,
+};
+use moka::{
+    future::Cache,
+    ops::compute::{CompResult, Op},
 };
-use moka::future::Cache;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, RwLock};
+use tokio::{
+    sync::{Notify, RwLock},
+    time::MissedTickBehavior,
+};
 use uuid::Uuid;
 
-pub type SocketId = usize;
+use crate::{
+    config,
+    outbound::{OutboundQueue, OverflowPolicy, QueueError, QueueMetrics, QueueStats},
+};
+
+// Identifies a connection for as long as the server runs, ids are never reused
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SocketId(u64);
+
+impl SocketId {
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // spreads sockets evenly over `shards`, always picking the same one for a socket
+    pub fn shard(self, shards: usize) -> usize {
+        (self.0 % shards as u64) as usize
+    }
+}
+
+impl fmt::Display for SocketId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Shard<T> = Receiver<TaggedMessage<T>>;
 
 #[derive(Clone, Debug)]
 pub struct TaggedMessage<T: for<'a> Deserialize<'a> + Send + Sync> {
@@ -21,36 +63,140 @@ pub struct TaggedMessage<T: for<'a> Deserialize<'a> + Send + Sync> {
     pub message: T,
 }
 
+// Unauthenticated connections per address, so one client can't fill the pool
+// with sockets that never log in
+pub struct Admissions {
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    max_per_ip: usize,
+}
+
+impl Admissions {
+    fn new(max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            per_ip: Mutex::new(HashMap::new()),
+            max_per_ip,
+        })
+    }
+
+    fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<Admission> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let count = per_ip.entry(ip).or_default();
+        if *count >= self.max_per_ip {
+            return None;
+        }
+
+        *count += 1;
+        Some(Admission {
+            admissions: self.clone(),
+            ip,
+        })
+    }
+
+    fn count(&self) -> usize {
+        self.per_ip.lock().unwrap().values().sum()
+    }
+}
+
+// Holds a slot of its address until the socket authenticates or is dropped
+pub struct Admission {
+    admissions: Arc<Admissions>,
+    ip: IpAddr,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut per_ip = self.admissions.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
 pub type Socket = Arc<SocketInner>;
 pub struct SocketInner {
     pub id: SocketId,
     pub user_id: RwLock<Option<Uuid>>,
-    sink: Mutex<SplitSink<WebSocket, Message>>,
+    outbound: Arc<OutboundQueue>,
+    last_pong: Mutex<Instant>,
+    admission: Mutex<Option<Admission>>,
+    // wakes the reader once the socket left the pool, the client may not
+    // answer the close frame and keep sending
+    removed: Notify,
 }
 
 impl SocketInner {
-    pub fn new(sink: SplitSink<WebSocket, Message>) -> (Socket, SocketId) {
-        let id = fastrand::usize(..);
+    pub fn new<S>(
+        sink: S,
+        admission: Option<Admission>,
+        config: &PoolConfig,
+        metrics: Arc<QueueMetrics>,
+    ) -> (Socket, SocketId)
+    where
+        S: Sink<Message, Error = axum::Error> + Send + Unpin + 'static,
+    {
+        let id = SocketId::next();
+        let outbound = OutboundQueue::new(sink, config.queue_capacity, config.overflow, metrics);
         (
             Arc::new(Self {
                 id,
                 user_id: RwLock::new(None),
-                sink: Mutex::new(sink),
+                outbound,
+                last_pong: Mutex::new(Instant::now()),
+                admission: Mutex::new(admission),
+                removed: Notify::new(),
             }),
             id,
         )
     }
 
-    pub async fn send(self: &Arc<Self>, message: String) -> Result<(), axum::Error> {
-        self.sink.lock().await.send(Message::Text(message)).await
+    pub fn send(self: &Arc<Self>, message: String) -> Result<(), QueueError> {
+        self.outbound.push(Message::Text(message))
+    }
+
+    pub fn close(self: &Arc<Self>, code: u16, reason: &'static str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+
+        self.outbound.close_with(Message::Close(Some(frame)));
+    }
+
+    pub fn ping(self: &Arc<Self>) -> Result<(), QueueError> {
+        self.outbound.push(Message::Ping(Vec::new()))
+    }
+
+    pub fn pong(self: &Arc<Self>) {
+        *self.last_pong.lock().unwrap() = Instant::now();
+    }
+
+    // true if nothing was heard back for longer than `timeout`
+    pub fn timed_out(self: &Arc<Self>, timeout: Duration) -> bool {
+        self.last_pong.lock().unwrap().elapsed() > timeout
+    }
+
+    pub fn queue_depth(self: &Arc<Self>) -> usize {
+        self.outbound.depth()
     }
 
     pub async fn authenticate(self: &Arc<Self>, user_id: Uuid) {
         self.user_id.write().await.replace(user_id);
+        self.admission.lock().unwrap().take();
     }
 
     pub async fn user_id(self: &Arc<Self>) -> Option<Uuid> {
-        self.user_id.read().await.clone()
+        *self.user_id.read().await
+    }
+
+    // forgets who the socket belonged to and stops its reader and writer
+    async fn remove(self: &Arc<Self>) -> Option<Uuid> {
+        let user_id = self.user_id.write().await.take();
+        self.outbound.close();
+        self.removed.notify_one();
+        user_id
     }
 }
 
@@ -66,6 +212,10 @@ impl ClientInner {
         self.0.write().await.push(socket);
     }
 
+    pub async fn socket_ids(self: &Client) -> Vec<SocketId> {
+        self.0.read().await.iter().map(|socket| socket.id).collect()
+    }
+
     pub async fn remove_socket(self: &Client, socket_id: SocketId) -> usize {
         let mut sockets = self.0.write().await;
         if let Some(pos) = sockets.iter().position(|s| s.id == socket_id) {
@@ -75,46 +225,189 @@ impl ClientInner {
         sockets.len()
     }
 
-    pub async fn send(self: &Client, message: String) {
+    // returns the sockets that could not be sent to, they are left for the
+    // pool to remove since that needs the lock this is holding
+    pub async fn send(self: &Client, message: String) -> Vec<SocketId> {
         let sockets = self.0.read().await;
+        let mut dead = Vec::new();
         for socket in sockets.iter() {
-            if let Err(e) = socket.send(message.clone()).await {
+            if let Err(e) = socket.send(message.clone()) {
                 println!("Error sending message to socket {}: {}", socket.id, e);
-                self.remove_socket(socket.id).await;
+                dead.push(socket.id);
             }
         }
+
+        dead
+    }
+}
+
+// A user came online with their first socket or went offline with their last
+#[derive(Clone, Copy, Debug)]
+pub enum PresenceEvent {
+    Online(Uuid),
+    Offline(Uuid),
+}
+
+pub struct PoolConfig {
+    // messages that can be waiting to be written to a single socket
+    pub queue_capacity: usize,
+    pub overflow: OverflowPolicy,
+    // incoming messages are split over this many queues by socket, so a slow
+    // message only holds up the sockets sharing its queue
+    pub dispatch_shards: usize,
+    // messages that can be waiting in a dispatch queue before readers wait
+    pub dispatch_capacity: usize,
+    // how often sockets are pinged
+    pub heartbeat_interval: Duration,
+    // heartbeats a socket can leave unanswered before it is dropped
+    pub heartbeat_misses: u32,
+    // how long a socket has to authenticate before it is closed
+    pub auth_timeout: Duration,
+    pub max_unauthenticated_per_ip: usize,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        Self {
+            queue_capacity: config::env_or("SOCKET_QUEUE_CAPACITY", 256),
+            overflow: config::env_or("SOCKET_QUEUE_OVERFLOW", OverflowPolicy::Disconnect),
+            dispatch_shards: config::env_or("DISPATCH_SHARDS", 32).max(1),
+            dispatch_capacity: config::env_or("DISPATCH_QUEUE_CAPACITY", 64),
+            // a zero interval would panic the heartbeat timer
+            heartbeat_interval: config::env_secs_or("HEARTBEAT_INTERVAL_SECS", 20)
+                .max(Duration::from_secs(1)),
+            heartbeat_misses: config::env_or("HEARTBEAT_MAX_MISSED", 3).max(1),
+            auth_timeout: config::env_secs_or("AUTH_TIMEOUT_SECS", 10),
+            max_unauthenticated_per_ip: config::env_or("MAX_UNAUTHENTICATED_PER_IP", 16),
+        }
     }
 }
 
+#[derive(Clone, Debug, Serialize)]
+pub struct PoolStats {
+    pub sockets: u64,
+    pub authenticated_users: u64,
+    pub unauthenticated_sockets: usize,
+    pub deepest_queue: usize,
+    #[serde(flatten)]
+    pub queues: QueueStats,
+}
+
 pub struct WsPool<T: for<'a> Deserialize<'a> + Send + Sync> {
+    config: PoolConfig,
+    metrics: Arc<QueueMetrics>,
+    admissions: Arc<Admissions>,
     authenticated: Cache<Uuid, Client>,
     sockets: Cache<SocketId, Socket>,
-    subscriber: UnboundedSender<TaggedMessage<T>>,
+    last_seen: Cache<Uuid, DateTime<Utc>>,
+    subscribers: Vec<Sender<TaggedMessage<T>>>,
+    presence: UnboundedSender<PresenceEvent>,
 }
 
 impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
-    pub fn new() -> (&'static WsPool<T>, UnboundedReceiver<TaggedMessage<T>>) {
-        let (tx, rx) = futures::channel::mpsc::unbounded();
+    const GOING_AWAY: u16 = 1001;
+    const AUTH_TIMEOUT: u16 = 4001;
+
+    pub fn new(
+        config: PoolConfig,
+    ) -> (
+        &'static WsPool<T>,
+        Vec<Shard<T>>,
+        UnboundedReceiver<PresenceEvent>,
+    ) {
+        let (subscribers, receivers) = (0..config.dispatch_shards)
+            .map(|_| futures::channel::mpsc::channel(config.dispatch_capacity))
+            .unzip();
+        let (presence_tx, presence_rx) = futures::channel::mpsc::unbounded();
         (
             leak(Self {
+                admissions: Admissions::new(config.max_unauthenticated_per_ip),
+                config,
+                metrics: Arc::default(),
                 sockets: Cache::builder().build(),
                 authenticated: Cache::builder().build(),
-                subscriber: tx,
+                last_seen: Cache::builder().build(),
+                subscribers,
+                presence: presence_tx,
             }),
-            rx,
+            receivers,
+            presence_rx,
         )
     }
 
-    pub async fn add_connection(&'static self, websocket: WebSocket) {
+    // reserves a slot for a new unauthenticated socket from `ip`, `None` if it has too many
+    pub fn admit(&self, ip: IpAddr) -> Option<Admission> {
+        self.admissions.admit(ip)
+    }
+
+    // sockets that authenticated while upgrading are added as `user_id` right away,
+    // others need an admission and have to authenticate before the deadline
+    pub async fn add_connection(
+        &'static self,
+        websocket: WebSocket,
+        admission: Option<Admission>,
+        user_id: Option<Uuid>,
+    ) -> SocketId {
         let (sink, mut stream) = websocket.split();
-        let (socket, socket_id) = SocketInner::new(sink);
+        let (socket, socket_id) =
+            SocketInner::new(sink, admission, &self.config, self.metrics.clone());
 
         self.add_socket(socket.clone()).await;
+        if let Some(user_id) = user_id {
+            self.authenticate(user_id, socket_id).await;
+        }
 
         tokio::task::spawn(async move {
             println!("Socket connected: {}", socket_id);
-            let mut subscriber = self.subscriber.clone();
-            while let Some(message) = stream.next().await {
+            // every message of a socket goes through the same queue, so they are handled in order
+            let mut subscriber = self.subscribers[socket_id.shard(self.subscribers.len())].clone();
+
+            let interval = self.config.heartbeat_interval;
+            let timeout = interval * self.config.heartbeat_misses;
+            let mut heartbeat =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            let auth_deadline = tokio::time::sleep(self.config.auth_timeout);
+            tokio::pin!(auth_deadline);
+            let mut awaiting_auth = true;
+
+            loop {
+                let message = tokio::select! {
+                    message = stream.next() => message,
+                    _ = socket.removed.notified() => break,
+                    _ = &mut auth_deadline, if awaiting_auth => {
+                        if socket.user_id().await.is_none() {
+                            println!("Socket did not authenticate in time: {}", socket_id);
+                            self.close_socket(socket_id, Self::AUTH_TIMEOUT, "Authentication timeout")
+                                .await;
+                            return;
+                        }
+
+                        awaiting_auth = false;
+                        continue;
+                    }
+                    _ = heartbeat.tick() => {
+                        if socket.timed_out(timeout) {
+                            println!("Socket timed out: {}", socket_id);
+                            self.close_socket(socket_id, Self::GOING_AWAY, "Heartbeat timeout")
+                                .await;
+                            return;
+                        }
+
+                        if let Err(e) = socket.ping() {
+                            println!("Error pinging socket {}: {}", socket_id, e);
+                            break;
+                        }
+
+                        continue;
+                    }
+                };
+
+                let Some(message) = message else {
+                    break;
+                };
+
                 let result: Result<(), Error> = try {
                     match message? {
                         Message::Text(text) => {
@@ -136,6 +429,7 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
 
                             subscriber.send(tagged_message).await?;
                         }
+                        Message::Pong(_) => socket.pong(),
                         Message::Close(_) => {
                             break;
                         }
@@ -152,6 +446,8 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
             println!("Socket disconnected: {}", socket_id);
             self.remove_socket(socket_id).await;
         });
+
+        socket_id
     }
 
     async fn add_socket(&'static self, socket: Socket) {
@@ -163,18 +459,76 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
             return;
         };
 
-        let Some(user_id) = socket.user_id().await else {
+        let Some(user_id) = socket.remove().await else {
             return;
         };
 
+        self.detach(user_id, socket_id).await;
+    }
+
+    // takes the socket out of the user's client, and the client out of the pool with
+    // its last socket, under the entry's lock so a socket authenticating can't join
+    // a client that is being dropped
+    async fn detach(&'static self, user_id: Uuid, socket_id: SocketId) {
+        let result = self
+            .authenticated
+            .entry(user_id)
+            .and_compute_with(|client| async move {
+                let Some(client) = client else {
+                    return Op::Nop;
+                };
+
+                match client.into_value().remove_socket(socket_id).await {
+                    0 => Op::Remove,
+                    _ => Op::Nop,
+                }
+            })
+            .await;
+
+        if let CompResult::Removed(_) = result {
+            self.last_seen.insert(user_id, Utc::now()).await;
+            self.publish_presence(PresenceEvent::Offline(user_id));
+        }
+    }
+
+    async fn remove_sockets(&'static self, socket_ids: Vec<SocketId>) {
+        for socket_id in socket_ids {
+            self.remove_socket(socket_id).await;
+        }
+    }
+
+    // sends a close frame and drops the socket from the pool
+    pub async fn close_socket(&'static self, socket_id: SocketId, code: u16, reason: &'static str) {
+        if let Some(socket) = self.sockets.get(&socket_id).await {
+            socket.close(code, reason);
+        }
+
+        self.remove_socket(socket_id).await;
+    }
+
+    // closes every socket of a user
+    pub async fn close_user(&'static self, user_id: Uuid, code: u16, reason: &'static str) {
         let Some(client) = self.authenticated.get(&user_id).await else {
             return;
         };
 
-        let open_sockets = client.remove_socket(socket_id).await;
+        for socket_id in client.socket_ids().await {
+            self.close_socket(socket_id, code, reason).await;
+        }
+    }
 
-        if open_sockets == 0 {
-            self.authenticated.remove(&user_id).await;
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            sockets: self.sockets.entry_count(),
+            authenticated_users: self.authenticated.entry_count(),
+            unauthenticated_sockets: self.admissions.count(),
+            deepest_queue: self
+                .sockets
+                .iter()
+                .map(|(_, socket)| socket.queue_depth())
+                .max()
+                .unwrap_or(0),
+            queues: self.metrics.stats(),
         }
     }
 
@@ -185,18 +539,51 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
 
         socket.authenticate(user_id).await;
 
-        // if the user is already authenticated, add the socket to the client
-        match self.authenticated.get(&user_id).await {
-            Some(client) => {
-                client.add_socket(socket).await;
-            }
-            None => {
-                let client = ClientInner::new(socket);
-                self.authenticated.insert(user_id, client).await;
-            }
+        // joins the user's client, or starts one if this is their first socket
+        let result = self
+            .authenticated
+            .entry(user_id)
+            .and_compute_with(|client| async move {
+                match client {
+                    Some(client) => {
+                        client.into_value().add_socket(socket).await;
+                        Op::Nop
+                    }
+                    None => Op::Put(ClientInner::new(socket)),
+                }
+            })
+            .await;
+
+        if let CompResult::Inserted(_) = result {
+            self.publish_presence(PresenceEvent::Online(user_id));
+        }
+
+        // the socket may have left while it was being added
+        if !self.is_connected(socket_id) {
+            self.detach(user_id, socket_id).await;
         }
     }
 
+    fn publish_presence(&self, event: PresenceEvent) {
+        if let Err(e) = self.presence.unbounded_send(event) {
+            println!("Failed to publish presence: {}", e);
+        }
+    }
+
+    // false once the socket was closed or dropped, even if it is still being read from
+    pub fn is_connected(&self, socket_id: SocketId) -> bool {
+        self.sockets.contains_key(&socket_id)
+    }
+
+    pub fn is_online(&self, user_id: &Uuid) -> bool {
+        self.authenticated.contains_key(user_id)
+    }
+
+    // when the user's last socket disconnected, if it did since we started
+    pub async fn last_seen(&self, user_id: &Uuid) -> Option<DateTime<Utc>> {
+        self.last_seen.get(user_id).await
+    }
+
     pub async fn send_to_user<M>(&'static self, user_id: Uuid, message: M) -> Result<(), Error>
     where
         M: Serialize,
@@ -206,7 +593,8 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
         };
 
         let message = serde_json::to_string(&message)?;
-        client.send(message).await;
+        let dead = client.send(message).await;
+        self.remove_sockets(dead).await;
 
         Ok(())
     }
@@ -225,7 +613,30 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
 
         let message = serde_json::to_string(&message)?;
 
-        socket.send(message).await.map_err(|e| e.into())
+        socket.send(message).map_err(|e| e.into())
+    }
+
+    // sends the message to every authenticated user the filter accepts,
+    // returns how many users it went to
+    pub async fn broadcast<M, F>(&'static self, message: M, filter: F) -> Result<usize, Error>
+    where
+        M: Serialize,
+        F: Fn(&Uuid) -> bool,
+    {
+        let message = serde_json::to_string(&message)?;
+        let clients: Vec<Client> = self
+            .authenticated
+            .iter()
+            .filter(|(user_id, _)| filter(user_id))
+            .map(|(_, client)| client)
+            .collect();
+
+        for client in &clients {
+            let dead = client.send(message.clone()).await;
+            self.remove_sockets(dead).await;
+        }
+
+        Ok(clients.len())
     }
 
     pub async fn send_to_users<M>(&'static self, user_ids: &[Uuid], message: M) -> Result<(), Error>
@@ -235,7 +646,8 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
         let message = serde_json::to_string(&message)?;
         for user_id in user_ids {
             if let Some(client) = self.authenticated.get(user_id).await {
-                client.send(message.clone()).await;
+                let dead = client.send(message.clone()).await;
+                self.remove_sockets(dead).await;
             }
         }
 
@@ -249,3 +661,185 @@ impl<T: for<'a> Deserialize<'a> + Send + Sync> WsPool<T> {
 pub fn leak<T>(t: T) -> &'static T {
     Box::leak(Box::new(t))
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn pool() -> &'static WsPool<()> {
+        pool_with_presence().0
+    }
+
+    fn pool_with_presence() -> (&'static WsPool<()>, UnboundedReceiver<PresenceEvent>) {
+        let (pool, _, presence) = WsPool::new(PoolConfig {
+            queue_capacity: 16,
+            overflow: OverflowPolicy::Disconnect,
+            dispatch_shards: 1,
+            dispatch_capacity: 1,
+            heartbeat_interval: Duration::from_secs(20),
+            heartbeat_misses: 3,
+            auth_timeout: Duration::from_secs(10),
+            max_unauthenticated_per_ip: 16,
+        });
+
+        (pool, presence)
+    }
+
+    // the server end of an in-memory socket, what it writes comes out of the receiver
+    async fn connect(
+        pool: &'static WsPool<()>,
+        user_id: Uuid,
+    ) -> (Socket, mpsc::UnboundedReceiver<Message>) {
+        let (tx, rx) = mpsc::unbounded();
+        let sink = tx.sink_map_err(axum::Error::new);
+        let admission = pool.admit(IpAddr::from([127, 0, 0, 1]));
+        let (socket, socket_id) =
+            SocketInner::new(sink, admission, &pool.config, pool.metrics.clone());
+
+        pool.add_socket(socket.clone()).await;
+        pool.authenticate(user_id, socket_id).await;
+
+        (socket, rx)
+    }
+
+    async fn next_text(rx: &mut mpsc::UnboundedReceiver<Message>) -> Option<String> {
+        match tokio::time::timeout(TIMEOUT, rx.next()).await {
+            Ok(Some(Message::Text(text))) => Some(text),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_send_evicts_socket_without_deadlocking() {
+        let pool = pool();
+        let user_id = Uuid::new_v4();
+
+        let (dead, dead_rx) = connect(pool, user_id).await;
+        let (alive, mut alive_rx) = connect(pool, user_id).await;
+
+        // the client went away, the socket's writer fails on its next message
+        drop(dead_rx);
+        pool.send_to_user(user_id, "first").await.unwrap();
+        tokio::time::timeout(TIMEOUT, async {
+            while !dead.outbound.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("writer did not notice the closed socket");
+
+        tokio::time::timeout(TIMEOUT, pool.send_to_user(user_id, "second"))
+            .await
+            .expect("sending to a user with a dead socket deadlocked")
+            .unwrap();
+
+        assert!(pool.sockets.get(&dead.id).await.is_none());
+        assert!(pool.sockets.get(&alive.id).await.is_some());
+
+        let client = pool.authenticated.get(&user_id).await.unwrap();
+        let remaining: Vec<_> = client.0.read().await.iter().map(|s| s.id).collect();
+        assert_eq!(remaining, vec![alive.id]);
+
+        assert_eq!(next_text(&mut alive_rx).await.as_deref(), Some("\"first\""));
+        assert_eq!(
+            next_text(&mut alive_rx).await.as_deref(),
+            Some("\"second\"")
+        );
+
+        // the user stays online through their other socket
+        tokio::time::timeout(TIMEOUT, pool.send_to_users(&[user_id], "third"))
+            .await
+            .expect("sending to users deadlocked")
+            .unwrap();
+        assert_eq!(next_text(&mut alive_rx).await.as_deref(), Some("\"third\""));
+        assert!(pool.is_online(&user_id));
+    }
+
+    #[tokio::test]
+    async fn last_dead_socket_takes_user_offline() {
+        let pool = pool();
+        let user_id = Uuid::new_v4();
+
+        let (dead, _rx) = connect(pool, user_id).await;
+        dead.close(1000, "Gone");
+
+        tokio::time::timeout(TIMEOUT, pool.send_to_user(user_id, "hello"))
+            .await
+            .expect("sending to a user with a dead socket deadlocked")
+            .unwrap();
+
+        assert!(pool.sockets.get(&dead.id).await.is_none());
+        assert!(!pool.is_online(&user_id));
+        assert!(pool.last_seen(&user_id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn closed_socket_stops_speaking_for_its_user() {
+        let pool = pool();
+        let user_id = Uuid::new_v4();
+
+        let (socket, _rx) = connect(pool, user_id).await;
+        pool.close_user(user_id, 4003, "Session revoked").await;
+
+        assert!(!pool.is_connected(socket.id));
+        assert_eq!(socket.user_id().await, None);
+        // the reader is woken even though it was not waiting yet
+        tokio::time::timeout(TIMEOUT, socket.removed.notified())
+            .await
+            .expect("the reader of a closed socket was not stopped");
+    }
+
+    #[tokio::test]
+    async fn removed_socket_closes_its_sink() {
+        let pool = pool();
+        let user_id = Uuid::new_v4();
+
+        let (socket, mut rx) = connect(pool, user_id).await;
+        pool.send_to_user(user_id, "last").await.unwrap();
+        pool.remove_socket(socket.id).await;
+
+        // what was queued is still written, then the writer lets go of the sink
+        assert_eq!(next_text(&mut rx).await.as_deref(), Some("\"last\""));
+        tokio::time::timeout(TIMEOUT, async { while rx.next().await.is_some() {} })
+            .await
+            .expect("the sink of a removed socket was never closed");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_sockets_of_a_user_share_one_client() {
+        let (pool, mut presence) = pool_with_presence();
+        let user_id = Uuid::new_v4();
+
+        let sockets: Vec<_> =
+            futures::future::join_all((0..8).map(|_| tokio::spawn(connect(pool, user_id))))
+                .await
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+
+        let client = pool.authenticated.get(&user_id).await.unwrap();
+        assert_eq!(client.socket_ids().await.len(), sockets.len());
+
+        futures::future::join_all(
+            sockets
+                .iter()
+                .map(|(socket, _)| tokio::spawn(pool.remove_socket(socket.id))),
+        )
+        .await;
+        assert!(!pool.is_online(&user_id));
+
+        // the user came online once and went offline once
+        let events: Vec<_> = std::iter::from_fn(|| presence.try_recv().ok()).collect();
+        assert!(
+            matches!(
+                events[..],
+                [PresenceEvent::Online(_), PresenceEvent::Offline(_)]
+            ),
+            "{events:?}"
+        );
+    }
+}
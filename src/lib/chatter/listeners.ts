@@ -1,7 +1,28 @@
-import { errorAlert } from "$lib/Alerts/stores";
+import { errorAlert, infoAlert, warningAlert } from "$lib/Alerts/stores";
 import { get } from "svelte/store";
 import { on_message, send_message } from "./msg";
-import { addMessages, addUsers, users, open, ping, authenicated } from "./stores";
+import {
+    addMessages,
+    addUsers,
+    addHistoryPage,
+    chatKey,
+    fetchHistories,
+    markRead,
+    setReceipt,
+    setTyping,
+    users,
+    open,
+    ping,
+    presence,
+    blocked,
+    reports,
+    authenicated,
+    resetChatState,
+    chat_order,
+    talking_to,
+    unread,
+    uuid
+} from "./stores";
 
 
 export function startListeners() {
@@ -11,18 +32,80 @@ export function startListeners() {
         authenicated.set(true);
     });
 
-    on_message("BulkUsers", ({ users }) => {
+    on_message("BulkUsers", ({ users, unread: counts }) => {
+        unread.set(counts);
+        // users are sorted by most recent activity
+        chat_order.set(users.map((user) => user.id));
         addUsers(users);
-        for (const user of users) {
-            send_message("SyncChat", { with: user.id });
-        }
+        send_message("QueryPresence", { users: users.map((user) => user.id) });
+        fetchHistories(users.map((user) => user.id));
     });
 
     on_message("UserMeta", ({ user }) => {
         addUsers([user]);
     });
 
+    on_message("BlockedUsers", ({ users }) => {
+        blocked.set(Object.fromEntries(users.map((id) => [id, true])));
+    });
+
+    on_message("Reports", ({ reports: open_reports }) => {
+        reports.set(open_reports);
+    });
+
+    on_message("ModerationNotice", ({ action }) => {
+        if (action.type === "Warn") {
+            warningAlert("A moderator warned you", action.message, 10000);
+        } else if (action.type === "Mute") {
+            warningAlert(`A moderator muted you for ${action.minutes} minutes`, "", 10000);
+        }
+    });
+
+    on_message("Announcement", ({ message }) => {
+        if (message.type === "Server") {
+            infoAlert("Announcement", message.message, 10000);
+        }
+    });
+
+    on_message("SessionRevoked", (_) => {
+        // the server closes the socket right after this
+        errorAlert("Your chat session has ended, please sign in again");
+        resetChatState();
+    });
+
     on_message("Error", (message) => {
+        if (typeof message.error === "object" && "RateLimited" in message.error) {
+            const seconds = Math.ceil(message.error.RateLimited.retry_after_ms / 1000);
+            errorAlert(`Slow down, try again in ${seconds}s`);
+            return;
+        }
+
+        if (typeof message.error === "object" && "Muted" in message.error) {
+            const until = new Date(message.error.Muted.until).toLocaleString();
+            errorAlert(`You are muted until ${until}`);
+            return;
+        }
+
+        if (message.error === "Forbidden") {
+            errorAlert("You are not allowed to do that");
+            return;
+        }
+
+        if (message.error === "Blocked") {
+            errorAlert("You can't message this user");
+            return;
+        }
+
+        // a server without token authentication, we fall back to the secret
+        if (message.error === "Unauthorized" && !get(authenicated)) {
+            return;
+        }
+
+        if (message.error === "Banned") {
+            errorAlert("You have been banned from chatting");
+            return;
+        }
+
         errorAlert("WS Error");
         console.error("WS Error: ", message);
     });
@@ -31,6 +114,10 @@ export function startListeners() {
         addMessages(participants, messages, true);
     });
 
+    on_message("HistoryPage", ({ participants, messages, cursor, has_more }) => {
+        addHistoryPage(participants, messages, cursor, has_more);
+    });
+
     on_message("DirectMessage", ({ participants, message }) => {
         const usrs = get(users);
         for (const participant of participants) {
@@ -43,5 +130,47 @@ export function startListeners() {
             ping.set(true);
         }
         addMessages(participants, [message]);
+
+        const key = chatKey(participants);
+        if (message.type === "User") {
+            setTyping(message.from, false);
+        }
+        if (message.type === "User" && message.from !== get(uuid)) {
+            unread.update((u) => {
+                u[key] = (u[key] ?? 0) + 1;
+                return u;
+            });
+
+            if (is_open && get(talking_to) === key) {
+                markRead(key);
+            }
+        }
+    });
+
+    on_message("Presence", ({ user, online, last_seen }) => {
+        presence.update((p) => {
+            p[user] = { online, last_seen };
+            return p;
+        });
+    });
+
+    on_message("Typing", ({ from, state }) => {
+        setTyping(from, state === "Started");
+    });
+
+    on_message("Delivered", ({ participants, message_id }) => {
+        setReceipt(chatKey(participants), { delivered: message_id });
+    });
+
+    on_message("ReadReceipt", ({ participants, reader, up_to }) => {
+        const key = chatKey(participants);
+        if (reader === get(uuid)) {
+            unread.update((u) => {
+                u[key] = 0;
+                return u;
+            });
+        } else {
+            setReceipt(key, { read: up_to });
+        }
     });
 }
\ No newline at end of file
@@ -1,7 +1,9 @@
-import type { ClientMessage, ClientMessageMap, ClientMessageTypes, ServerErrors, ServerMessage, ServerMessageMap, ServerMessageTypes } from "$lib/messages";
+import type { ClientRequest, ClientMessageMap, ClientMessageTypes, ServerErrors, ServerMessage, ServerMessageMap, ServerMessageTypes } from "$lib/messages";
 
 import { socket } from "./stores";
 
+const ACK_TIMEOUT = 10_000;
+
 const promises = new Map<string, (value: any) => void>();
 const callbacks = new Map<string, ((value: any) => void)[]>();
 
@@ -9,6 +11,13 @@ export function handle_message(message: ServerMessage) {
     // console.log("message from server", message);
     // console.log(callbacks);
 
+    // settle the promise of the message this is a response to
+    if (message.type === "Ack") {
+        resolve_message_promise(message.nonce, null);
+    } else if (message.type === "Error" && message.nonce) {
+        resolve_message_promise(message.nonce, message.error);
+    }
+
     const cbs = callbacks.get(message.type) ?? [];
     for (const cb of cbs) {
         cb(message);
@@ -34,19 +43,23 @@ export function on_message<T extends ServerMessageTypes>(type: T, cb: (message:
     callbacks.set(type, cbs);
 }
 
-export async function send_message<T extends ClientMessageTypes>(type: T, value: ClientMessageMap<T>): Promise<ServerErrors> {
+// resolves with null once the server acknowledged the message, or with the error it produced
+export async function send_message<T extends ClientMessageTypes>(type: T, value: ClientMessageMap<T>): Promise<ServerErrors | null> {
     if (!socket) {
         return "Internal";
     }
 
-    const message_id = Math.random().toString(36).substring(2);
+    const nonce = Math.random().toString(36).substring(2);
 
-    const message: ClientMessage = { type, ...value } as any;
+    const message: ClientRequest = { type, nonce, ...value } as any;
     const serialized = JSON.stringify(message);
 
     socket.send(serialized);
 
     return new Promise((resolve) => {
-        promises.set(message_id, resolve);
+        promises.set(nonce, resolve);
+
+        // the socket may close before the server answers
+        setTimeout(() => resolve_message_promise(nonce, "Internal"), ACK_TIMEOUT);
     });
-}
\ No newline at end of file
+}
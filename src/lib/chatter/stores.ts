@@ -2,7 +2,7 @@ import { PUBLIC_CHATTER_WS_URL } from "$env/static/public";
 import { get, writable } from "svelte/store";
 import { handle_message, send_message } from "./msg";
 import { startListeners } from "./listeners";
-import type { ChatMessage, ChatUser } from "$lib/messages";
+import type { ChatMessage, ChatUser, Report } from "$lib/messages";
 
 export let socket: WebSocket | null;
 export type SocketState =  "connecting" | "connected" | "authenticated" | "disconnected";
@@ -12,6 +12,8 @@ export const socket_state = writable<SocketState>("connecting");
 export const authenicated = writable(false);
 
 export const uuid = writable("");
+export const stoken = writable("");
+// the `verify` secret, only used when the server doesn't accept access tokens
 export const ssecret = writable("");
 
 export const open = writable(false);
@@ -28,6 +30,26 @@ export const chat_order = writable<string[]>([]);
 export const messages = writable<{ [from: string]: ChatMessage[] }>({});
 export const users = writable<{ [id: string]: string }>({});
 
+export const unread = writable<{ [with_id: string]: number }>({});
+// the last own message the other participant received and read
+export const receipts = writable<{ [with_id: string]: { delivered?: string; read?: string } }>({});
+
+export const presence = writable<{ [id: string]: { online: boolean; last_seen: string | null } }>({});
+
+// users we blocked
+export const blocked = writable<{ [id: string]: boolean }>({});
+
+// open reports, only sent to admins
+export const reports = writable<Report[]>([]);
+
+// users currently typing to us
+export const typing = writable<{ [from: string]: boolean }>({});
+
+export type HistoryState = { cursor: string | null; has_more: boolean; loading: boolean };
+export const history = writable<{ [with_id: string]: HistoryState }>({});
+
+const HISTORY_PAGE_SIZE = 30;
+
 export function startChat(user_id: string, topic: string = "") {
     talking_to.set(user_id);
     open.set(true);
@@ -46,6 +68,123 @@ export function resetChatState() {
     authenicated.set(false);
     users.set({});
     messages.set({});
+    history.set({});
+    unread.set({});
+    receipts.set({});
+    typing.set({});
+    presence.set({});
+    blocked.set({});
+    reports.set([]);
+}
+
+const TYPING_REFRESH = 2000;
+let last_typing: { to: string; at: number } | null = null;
+
+// called on every keystroke, the server is only told every few seconds
+export function sendTyping(to: string) {
+    const now = Date.now();
+    if (last_typing && last_typing.to === to && now - last_typing.at < TYPING_REFRESH) {
+        return;
+    }
+
+    last_typing = { to, at: now };
+    send_message("Typing", { to, state: "Started" });
+}
+
+export function stopTyping(to: string) {
+    if (!last_typing || last_typing.to !== to) {
+        return;
+    }
+
+    last_typing = null;
+    send_message("Typing", { to, state: "Stopped" });
+}
+
+export function setTyping(from: string, is_typing: boolean) {
+    typing.update((t) => {
+        t[from] = is_typing;
+        return t;
+    });
+}
+
+export async function reportMessage(message_id: string, reason: string) {
+    const error = await send_message("ReportMessage", { message_id, reason });
+    return error === null;
+}
+
+export function chatKey(participants: string[]) {
+    const [from, to] = participants;
+    return from === get(uuid) ? to : from;
+}
+
+export function markRead(with_id: string) {
+    if (!get(unread)[with_id]) {
+        return;
+    }
+
+    const msgs = get(messages)[with_id] ?? [];
+    const last = msgs[msgs.length - 1];
+    if (last) {
+        send_message("MarkRead", { with: with_id, up_to: last.id });
+    }
+}
+
+export function setReceipt(with_id: string, receipt: { delivered?: string; read?: string }) {
+    receipts.update((r) => {
+        r[with_id] = { ...r[with_id], ...receipt };
+        return r;
+    });
+}
+
+// asks for the page before the oldest loaded message (or the latest page)
+export async function fetchHistory(with_id: string) {
+    const state = get(history)[with_id];
+    if (state && (state.loading || !state.has_more)) {
+        return;
+    }
+
+    const before = state?.cursor ?? null;
+    history.update((h) => {
+        h[with_id] = { cursor: before, has_more: true, loading: true };
+        return h;
+    });
+
+    const error = await send_message("FetchHistory", { with: with_id, before, limit: HISTORY_PAGE_SIZE });
+    if (error !== null) {
+        // no page is coming, let the next scroll ask again
+        history.update((h) => {
+            h[with_id] = { cursor: before, has_more: true, loading: false };
+            return h;
+        });
+    }
+}
+
+// one request at a time, so having many chats doesn't trip the rate limits on login
+export async function fetchHistories(with_ids: string[]) {
+    for (const with_id of with_ids) {
+        await fetchHistory(with_id);
+    }
+}
+
+export function addHistoryPage(participants: string[], page: ChatMessage[], cursor: string | null, has_more: boolean) {
+    const [from, to] = participants;
+    const key = from === get(uuid) ? to : from;
+
+    // without a cursor this is the latest page, which replaces what we have
+    const latest = !get(history)[key]?.cursor;
+    if (latest) {
+        addMessages(participants, page, true);
+    } else {
+        messages.update((msgs) => {
+            msgs[key] = [...page, ...(msgs[key] ?? [])];
+            return msgs;
+        });
+    }
+
+    history.update((h) => {
+        h[key] = { cursor, has_more, loading: false };
+        return h;
+    });
 }
 
 export function addMessages(participants: string[], bulk_msgs: ChatMessage[], clear: boolean = false) {
@@ -62,13 +201,24 @@ export function addMessages(participants: string[], bulk_msgs: ChatMessage[], cl
         }
 
         for (const msg of bulk_msgs) {
-            msgs[key].push(msg);
+            // a message can arrive both live and in a history page
+            if (!msgs[key].some((m) => m.id === msg.id)) {
+                msgs[key].push(msg);
+            }
         }
 
         return msgs;
     });
 
     chat_order.update((order) => {
+        // chat history keeps the order given by the server
+        if (clear) {
+            if (!order.includes(key)) {
+                order.push(key);
+            }
+            return order;
+        }
+
         // remove the key from the order
         order = order.filter((k) => k !== key);
         // add it to the front
@@ -87,13 +237,19 @@ export function addUsers(usrs: ChatUser[]) {
 }
 
 
+// close code the server uses when a socket never authenticates
+const AUTH_TIMEOUT = 4001;
+
 let started = false;
+let gave_up = false;
 export function connect_websocket() {
     if (!started) {
         startListeners();
         started = true;
     }
 
+    gave_up = false;
+
     if (socket) {
         return;
     }
@@ -101,6 +257,7 @@ export function connect_websocket() {
     socket_state.set("connecting");
 
     // console.log("Connecting to Websocket", PUBLIC_CHATTER_WS_URL);
+    // the token is sent once the socket is open, urls end up in access logs
     socket = new WebSocket(PUBLIC_CHATTER_WS_URL);
 
     socket.onopen = (_) => {
@@ -109,8 +266,14 @@ export function connect_websocket() {
         send_message("Ping", {})
     }
 
-    socket.onclose = (_) => {
+    socket.onclose = (event) => {
+        // the server gave up waiting for us to authenticate, asking again won't help
+        if (event.code === AUTH_TIMEOUT) {
+            gave_up = true;
+        }
+
         socket = null;
+        authenicated.set(false);
         socket_state.set("disconnected");
     }
 
@@ -122,6 +285,7 @@ export function connect_websocket() {
     socket.onerror = (event) => {
         console.error("WebSocket error", event);
         socket = null;
+        authenicated.set(false);
         socket_state.set("disconnected");
     }
 }
@@ -163,10 +327,15 @@ function debounce<F extends (...args: any[]) => any>(
 }
 
 const retry_time = 3;
-const retry_connect = debounce(connect_websocket, retry_time * 1000, () => {});
+// only reconnect while signed in, the session may have ended during the wait
+const retry_connect = debounce(() => {
+    if (!gave_up && get(stoken).length > 0) {
+        connect_websocket();
+    }
+}, retry_time * 1000, () => {});
 
 socket_state.subscribe((state) => {
-    if (state === "disconnected") {
+    if (state === "disconnected" && !gave_up && get(stoken).length > 0) {
         retry_connect();
     }
     if (state === "connected") {
@@ -175,15 +344,48 @@ socket_state.subscribe((state) => {
 });
 
 
-ssecret.subscribe((secret) => {
-    tryAuthenticating({ secret });
+// cleared once the server answers a token with `Unauthorized`, it has no JWT secret
+let token_auth = true;
+
+stoken.subscribe((token) => {
+    tryAuthenticating({ token });
+});
+
+ssecret.subscribe((_) => {
+    if (!token_auth) {
+        tryAuthenticating();
+    }
 });
 
-export function tryAuthenticating({ id, secret }: { id?: string, secret?: string } = {}) {
-    id = id || get(uuid);
-    secret = secret || get(ssecret);
+export async function tryAuthenticating({ token }: { token?: string } = {}) {
+    token = token || get(stoken);
 
-    if (secret.length > 0 && id.length > 0) {
+    if (get(authenicated)) {
+        return;
+    }
+
+    if (token_auth) {
+        if (token.length === 0) {
+            return;
+        }
+
+        const error = await send_message(
+            'AuthenticateToken',
+            {
+                token
+            }
+        )
+
+        if (error !== "Unauthorized") {
+            return;
+        }
+
+        token_auth = false;
+    }
+
+    const id = get(uuid);
+    const secret = get(ssecret);
+    if (id.length > 0 && secret.length > 0) {
         send_message(
             'Authenticate',
             {
@@ -193,6 +395,3 @@ export function tryAuthenticating({ id, secret }: { id?: string, secret?: string
         )
     }
 }
-
-
-
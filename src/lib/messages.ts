@@ -1,10 +1,16 @@
 /** this file is automatically generated, do not edit **/
 
-export type ChatUser = { id: string; email: string };
-export type ServerMessage = { type: "Pong" } | { type: "Authenticated" } | ({ type: "Error" } & ServerErrors) | { type: "UserMeta"; user: ChatUser } | { type: "BulkUsers"; users: ChatUser[] } | { type: "BulkMessages"; participants: string[]; messages: ChatMessage[] } | { type: "DirectMessage"; participants: string[]; message: ChatMessage };
-export type ServerErrors = "Internal" | "Unauthorized" | "AlreadyAuthenticated" | "InvalidUuid" | "InvalidSecret" | "InvalidMessage" | "InvalidUser" | "RateLimited";
-export type ClientMessage = { type: "Ping" } | { type: "Disconnect" } | { type: "Authenticate"; id: string; secret: string } | { type: "SyncChat"; with: string } | { type: "DirectMessage"; to: string; message: string } | { type: "SetTopic"; to: string; topic: string } | { type: "UserMeta"; with: string } | { type: "SyncChatUsers" };
-export type ChatMessage = { type: "User"; from: string; message: string } | { type: "Topic"; topic: string } | { type: "Server"; message: string };
+export type ChatUser = { id: string; email: string; role: Role };
+export type Role = "admin" | "user";
+export type ServerMessage = { type: "Pong" } | { type: "Authenticated" } | { type: "Ack"; nonce: string } | { type: "Error"; nonce: string | null; error: ServerErrors } | { type: "UserMeta"; user: ChatUser } | { type: "BulkUsers"; users: ChatUser[]; unread: { [key: string]: number } } | { type: "BulkMessages"; participants: string[]; messages: ChatMessage[] } | { type: "DirectMessage"; participants: string[]; message: ChatMessage } | { type: "HistoryPage"; participants: string[]; messages: ChatMessage[]; cursor: string | null; has_more: boolean } | { type: "Delivered"; participants: string[]; message_id: string } | { type: "ReadReceipt"; participants: string[]; reader: string; up_to: string } | { type: "Typing"; from: string; state: TypingState } | { type: "Presence"; user: string; online: boolean; last_seen: string | null } | { type: "BlockedUsers"; users: string[] } | { type: "Reports"; reports: Report[] } | { type: "ModerationNotice"; action: ReportAction } | { type: "Announcement"; message: ChatMessage } | { type: "SessionRevoked" };
+export type ServerErrors = "Internal" | "Unauthorized" | "AlreadyAuthenticated" | "InvalidUuid" | "InvalidSecret" | "InvalidToken" | "InvalidMessage" | "InvalidUser" | "Banned" | "Blocked" | "Forbidden" | { Muted: { until: string } } | { RateLimited: { retry_after_ms: number } };
+export type ClientMessage = { type: "Ping" } | { type: "Disconnect" } | { type: "Authenticate"; id: string; secret: string } | { type: "AuthenticateToken"; token: string } | { type: "SyncChat"; with: string } | { type: "DirectMessage"; to: string; message: string } | { type: "SetTopic"; to: string; topic: string } | { type: "FetchHistory"; with: string; before: string | null; limit: number } | { type: "MarkRead"; with: string; up_to: string } | { type: "Typing"; to: string; state: TypingState } | { type: "QueryPresence"; users: string[] } | { type: "UserMeta"; with: string } | { type: "SyncChatUsers" } | { type: "Block"; user: string } | { type: "Unblock"; user: string } | { type: "ReportMessage"; message_id: string; reason: string } | { type: "Admin"; command: AdminCommand };
+export type ClientRequest = ({ type: "Ping" } | { type: "Disconnect" } | { type: "Authenticate"; id: string; secret: string } | { type: "AuthenticateToken"; token: string } | { type: "SyncChat"; with: string } | { type: "DirectMessage"; to: string; message: string } | { type: "SetTopic"; to: string; topic: string } | { type: "FetchHistory"; with: string; before: string | null; limit: number } | { type: "MarkRead"; with: string; up_to: string } | { type: "Typing"; to: string; state: TypingState } | { type: "QueryPresence"; users: string[] } | { type: "UserMeta"; with: string } | { type: "SyncChatUsers" } | { type: "Block"; user: string } | { type: "Unblock"; user: string } | { type: "ReportMessage"; message_id: string; reason: string } | { type: "Admin"; command: AdminCommand }) & { nonce: string | null };
+export type ChatMessage = { type: "User"; id: string; sent_at: string; from: string; message: string } | { type: "Topic"; id: string; sent_at: string; topic: string } | { type: "Server"; id: string; sent_at: string; message: string };
+export type TypingState = "Started" | "Stopped";
+export type AdminCommand = { type: "ListReports" } | { type: "ResolveReport"; report_id: string; action: ReportAction } | { type: "ReadChat"; between: string[]; before: string | null; limit: number } | { type: "Broadcast"; message: string; users: string[] | null } | { type: "MuteUser"; user: string; minutes: number } | { type: "DisconnectUser"; user: string };
+export type ReportAction = { type: "Dismiss" } | { type: "Warn"; message: string } | { type: "Mute"; minutes: number } | { type: "Ban" };
+export type Report = { id: string; reporter: ChatUser; reported: ChatUser; message_id: string; reason: string; context: ChatMessage[]; created_at: string };
 
 export type ClientMessageTypes = ClientMessage["type"];
 export type ServerMessageTypes = ServerMessage["type"];
@@ -0,0 +1,20 @@
+-- chat history written by the chatter service
+CREATE TYPE chat_message_kind AS ENUM ('user', 'topic', 'server');
+
+CREATE TABLE messages (
+    id BIGSERIAL PRIMARY KEY NOT NULL,
+    -- participants are stored ordered so (user_a, user_b) identifies a conversation
+    user_a UUID NOT NULL REFERENCES auth.users(id),
+    user_b UUID NOT NULL REFERENCES auth.users(id),
+    kind chat_message_kind NOT NULL,
+    sender UUID REFERENCES auth.users(id),
+    body TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK (user_a <= user_b)
+);
+
+CREATE INDEX messages_conversation_idx ON messages (user_a, user_b, id DESC);
+
+-- only the chatter service (which bypasses RLS) reads and writes messages
+ALTER TABLE
+    messages ENABLE ROW LEVEL SECURITY;
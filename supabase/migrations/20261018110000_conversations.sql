@@ -0,0 +1,31 @@
+-- open chats of the chatter service, one row per pair of participants
+CREATE TABLE conversations (
+    -- participants are stored ordered, the same way as in messages
+    user_a UUID NOT NULL REFERENCES auth.users(id),
+    user_b UUID NOT NULL REFERENCES auth.users(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_message_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_a, user_b),
+    CHECK (user_a <= user_b)
+);
+
+CREATE INDEX conversations_user_a_idx ON conversations (user_a, last_message_at DESC);
+
+CREATE INDEX conversations_user_b_idx ON conversations (user_b, last_message_at DESC);
+
+-- conversations that already have history
+INSERT INTO
+    conversations (user_a, user_b, created_at, last_message_at)
+SELECT
+    user_a,
+    user_b,
+    MIN(created_at),
+    MAX(created_at)
+FROM
+    messages
+GROUP BY
+    user_a,
+    user_b;
+
+ALTER TABLE
+    conversations ENABLE ROW LEVEL SECURITY;
@@ -0,0 +1,20 @@
+-- messages get the id assigned by the chatter service (UUIDv7),
+-- the serial column is kept to order messages within a conversation
+ALTER TABLE
+    messages RENAME COLUMN id TO seq;
+
+ALTER TABLE
+    messages
+ADD
+    COLUMN id UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+
+ALTER TABLE
+    messages
+ALTER COLUMN
+    id DROP DEFAULT;
+
+-- created_at holds the time the chatter service sent the message
+ALTER TABLE
+    messages
+ALTER COLUMN
+    created_at DROP DEFAULT;
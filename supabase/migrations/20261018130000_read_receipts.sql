@@ -0,0 +1,7 @@
+-- read cursors of both participants, the seq of the last message each has read
+ALTER TABLE
+    conversations
+ADD
+    COLUMN user_a_read_seq BIGINT NOT NULL DEFAULT 0,
+ADD
+    COLUMN user_b_read_seq BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,28 @@
+-- banned users can't use the chat, their open sockets are closed
+ALTER TABLE
+    user_info
+ADD
+    COLUMN banned BOOLEAN NOT NULL DEFAULT false;
+
+-- tells the chatter service to end the sessions of a user whose secret
+-- was regenerated or who was banned
+CREATE FUNCTION public .notify_session_revoked() RETURNS TRIGGER LANGUAGE PLPGSQL AS $$ BEGIN
+    PERFORM pg_notify('session_revoked', NEW .id :: text);
+
+RETURN NEW;
+
+END;
+
+$$;
+
+CREATE TRIGGER verify_secret_revoked_trigger
+AFTER
+UPDATE
+    OF secret ON verify FOR EACH ROW
+    WHEN (OLD .secret IS DISTINCT FROM NEW .secret) EXECUTE PROCEDURE public .notify_session_revoked();
+
+CREATE TRIGGER user_info_banned_trigger
+AFTER
+UPDATE
+    OF banned ON user_info FOR EACH ROW
+    WHEN (NEW .banned AND NOT OLD .banned) EXECUTE PROCEDURE public .notify_session_revoked();
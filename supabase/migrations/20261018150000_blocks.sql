@@ -0,0 +1,14 @@
+-- users who don't want to hear from someone in the chat
+CREATE TABLE blocks (
+    blocker UUID NOT NULL REFERENCES auth.users(id),
+    blocked UUID NOT NULL REFERENCES auth.users(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (blocker, blocked),
+    CHECK (blocker <> blocked)
+);
+
+CREATE INDEX blocks_blocked_idx ON blocks (blocked);
+
+-- only the chatter service (which bypasses RLS) reads and writes blocks
+ALTER TABLE
+    blocks ENABLE ROW LEVEL SECURITY;
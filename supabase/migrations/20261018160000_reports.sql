@@ -0,0 +1,30 @@
+-- messages reported to the admins, with the conversation around them as it was
+CREATE TABLE reports (
+    id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+    reporter UUID NOT NULL REFERENCES auth.users(id),
+    reported UUID NOT NULL REFERENCES auth.users(id),
+    message_id UUID NOT NULL REFERENCES messages(id),
+    reason TEXT NOT NULL,
+    context JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- set once an admin dealt with the report
+    resolved_by UUID REFERENCES auth.users(id),
+    resolved_at TIMESTAMPTZ,
+    resolution JSONB,
+    -- a message is only reported once by the same user
+    UNIQUE (reporter, message_id)
+);
+
+CREATE INDEX reports_open_idx ON reports (created_at)
+WHERE
+    resolved_at IS NULL;
+
+-- only the chatter service (which bypasses RLS) reads and writes reports
+ALTER TABLE
+    reports ENABLE ROW LEVEL SECURITY;
+
+-- muted users can read but not send messages until then
+ALTER TABLE
+    user_info
+ADD
+    COLUMN muted_until TIMESTAMPTZ;
@@ -0,0 +1,30 @@
+-- messages that tripped a content filter rule, kept for the moderators
+CREATE TABLE filter_hits (
+    id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+    sender UUID NOT NULL REFERENCES auth.users(id),
+    recipient UUID NOT NULL REFERENCES auth.users(id),
+    rule TEXT NOT NULL,
+    action TEXT NOT NULL CHECK (action IN ('allow', 'redact', 'reject')),
+    -- the message as it was sent, before anything was redacted
+    body TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX filter_hits_sender_idx ON filter_hits (sender, created_at DESC);
+
+ALTER TABLE
+    filter_hits ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Admins can read filter hits" ON filter_hits FOR
+SELECT
+    TO authenticated USING (
+        EXISTS (
+            SELECT
+                1
+            FROM
+                public .user_info
+            WHERE
+                id = auth.uid()
+                AND role = 'admin'
+        )
+    );
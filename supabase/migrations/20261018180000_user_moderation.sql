@@ -0,0 +1,39 @@
+-- sanctions are kept out of user_info, which everyone can read and which is
+-- part of the realtime publication
+CREATE TABLE user_moderation (
+    id UUID NOT NULL PRIMARY KEY REFERENCES auth.users(id),
+    -- banned users can't use the chat, their open sockets are closed
+    banned BOOLEAN NOT NULL DEFAULT false
+);
+
+INSERT INTO
+    user_moderation (id, banned)
+SELECT
+    id,
+    banned
+FROM
+    user_info
+WHERE
+    banned;
+
+DROP TRIGGER user_info_banned_trigger ON user_info;
+
+ALTER TABLE
+    user_info DROP COLUMN banned;
+
+-- only the chatter service (which bypasses RLS) reads and writes sanctions
+ALTER TABLE
+    user_moderation ENABLE ROW LEVEL SECURITY;
+
+-- rows are only created once a user is sanctioned
+CREATE TRIGGER user_moderation_banned_insert_trigger
+AFTER
+INSERT
+    ON user_moderation FOR EACH ROW
+    WHEN (NEW .banned) EXECUTE PROCEDURE public .notify_session_revoked();
+
+CREATE TRIGGER user_moderation_banned_trigger
+AFTER
+UPDATE
+    OF banned ON user_moderation FOR EACH ROW
+    WHEN (NEW .banned AND NOT OLD .banned) EXECUTE PROCEDURE public .notify_session_revoked();
@@ -0,0 +1,21 @@
+-- muted users can read but not send messages until then
+ALTER TABLE
+    user_moderation
+ADD
+    COLUMN muted_until TIMESTAMPTZ;
+
+INSERT INTO
+    user_moderation (id, muted_until)
+SELECT
+    id,
+    muted_until
+FROM
+    user_info
+WHERE
+    muted_until > CURRENT_TIMESTAMP ON CONFLICT (id) DO
+UPDATE
+SET
+    muted_until = EXCLUDED .muted_until;
+
+ALTER TABLE
+    user_info DROP COLUMN muted_until;
@@ -0,0 +1,29 @@
+-- access tokens issued before this are refused, they stay valid at Supabase
+-- until they expire
+ALTER TABLE
+    user_moderation
+ADD
+    COLUMN sessions_revoked_at TIMESTAMPTZ;
+
+CREATE FUNCTION public .record_sessions_revoked() RETURNS TRIGGER LANGUAGE PLPGSQL SECURITY DEFINER
+set
+    search_path = public AS $$ BEGIN
+        INSERT INTO
+            public .user_moderation (id, sessions_revoked_at)
+        VALUES
+            (NEW .id, CURRENT_TIMESTAMP) ON CONFLICT (id) DO
+        UPDATE
+        SET
+            sessions_revoked_at = EXCLUDED .sessions_revoked_at;
+
+RETURN NEW;
+
+END;
+
+$$;
+
+CREATE TRIGGER verify_secret_sessions_revoked_trigger
+AFTER
+UPDATE
+    OF secret ON verify FOR EACH ROW
+    WHEN (OLD .secret IS DISTINCT FROM NEW .secret) EXECUTE PROCEDURE public .record_sessions_revoked();
@@ -0,0 +1,29 @@
+-- UUIDv7 like the ids the chatter service creates, for messages it stores in
+-- bulk: the millisecond timestamp over the first 48 bits of a random UUID,
+-- with the version bits turned from 4 into 7
+CREATE FUNCTION public .uuid_generate_v7() RETURNS UUID LANGUAGE SQL VOLATILE AS $$
+SELECT
+    encode(
+        set_bit(
+            set_bit(
+                overlay(
+                    uuid_send(gen_random_uuid()) placing substring(
+                        int8send(
+                            floor(extract(epoch FROM clock_timestamp()) * 1000) :: BIGINT
+                        )
+                        FROM
+                            3
+                    )
+                    FROM
+                        1 FOR 6
+                ),
+                52,
+                1
+            ),
+            53,
+            1
+        ),
+        'hex'
+    ) :: UUID;
+
+$$;